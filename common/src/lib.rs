@@ -9,7 +9,11 @@ pub enum MutationStatus {
     Killed,
     NotKilled,
     Ignored,
+    /// Survived, but reviewed as behaving exactly like the original code.
+    Equivalent,
     Error,
+    Stale,
+    Retired,
 }
 
 // to string
@@ -22,12 +26,15 @@ impl std::fmt::Display for MutationStatus {
             MutationStatus::Killed => write!(f, "Killed"),
             MutationStatus::NotKilled => write!(f, "NotKilled"),
             MutationStatus::Ignored => write!(f, "Ignored"),
+            MutationStatus::Equivalent => write!(f, "Equivalent"),
             MutationStatus::Error => write!(f, "Error"),
+            MutationStatus::Stale => write!(f, "Stale"),
+            MutationStatus::Retired => write!(f, "Retired"),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
 pub struct Mutation {
     pub id: i64,
     pub patch_md5: String,
@@ -41,6 +48,27 @@ pub struct Mutation {
     pub end_time: Option<i64>,
     pub stderr: Option<String>,
     pub stdout: Option<String>,
+    pub fingerprint: Option<String>,
+    pub mutator: Option<String>,
+    pub base_commit: Option<String>,
+    pub retired_reason: Option<String>,
+    pub worker: Option<String>,
+    /// Registered worker holding the lease, as several can share a token.
+    #[serde(default)]
+    pub worker_id: Option<i64>,
+    pub lease_expires_at: Option<i64>,
+    #[serde(default)]
+    pub attempts: i64,
+    pub campaign_id: Option<i64>,
+    /// Follow-up on a survivor: open, test-written, wont-fix or closed.
+    pub task_state: Option<String>,
+    pub assignee: Option<String>,
+    /// Mutations with a higher priority run first within their campaign.
+    #[serde(default)]
+    pub priority: i64,
+    /// Capabilities a worker needs to run the mutation, e.g. `tsan`.
+    #[serde(default)]
+    pub requires: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -49,4 +77,96 @@ pub struct MutationResult {
     pub status: MutationStatus,
     pub stdout: Option<String>,
     pub stderr: Option<String>,
+    /// Exit code of the build and test command, when it ran to the end.
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetiredMutation {
+    pub mutation_id: i64,
+    pub reason: String,
+}
+
+/// Outcome of moving pending mutations onto a new base commit.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RebaseResult {
+    pub base_commit: String,
+    pub rebased: Vec<Mutation>,
+    pub retired: Vec<RetiredMutation>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum CampaignState {
+    Open,
+    Completed,
+    Archived,
+}
+
+impl std::fmt::Display for CampaignState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CampaignState::Open => write!(f, "Open"),
+            CampaignState::Completed => write!(f, "Completed"),
+            CampaignState::Archived => write!(f, "Archived"),
+        }
+    }
+}
+
+/// A named run grouping the mutations generated for a PR, a set of files or
+/// a diff. `source_ref` holds the PR number, files or diff base.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Campaign {
+    pub id: i64,
+    pub name: String,
+    pub base_commit: Option<String>,
+    pub source: String,
+    pub source_ref: Option<String>,
+    pub creator: String,
+    pub created_at: i64,
+    pub state: String,
+    /// Campaigns with a higher priority are worked on first.
+    pub priority: i64,
+    /// Capabilities a worker needs to run any mutation of the campaign.
+    pub requires: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NewCampaign {
+    pub name: String,
+    pub base_commit: Option<String>,
+    pub source: String,
+    pub source_ref: Option<String>,
+    /// Defaults to 0 for a new campaign. When given for an existing one it
+    /// has to match, as priorities are then changed by admins.
+    #[serde(default)]
+    pub priority: Option<i64>,
+    /// Like `priority`, defaults to no requirements.
+    #[serde(default)]
+    pub requires: Option<Vec<String>>,
+}
+
+/// Sent by a worker when it starts.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WorkerRegistration {
+    pub hostname: String,
+    pub version: String,
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Worker {
+    pub id: i64,
+    pub owner: String,
+    pub hostname: String,
+    pub version: String,
+    pub capabilities: Vec<String>,
+    pub current_mutation: Option<i64>,
+    pub registered_at: i64,
+    pub last_heartbeat: i64,
+    pub killed: i64,
+    pub not_killed: i64,
+    pub timeouts: i64,
+    pub errors: i64,
+    pub expired_leases: i64,
 }
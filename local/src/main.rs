@@ -0,0 +1,165 @@
+use clap::Parser;
+use common::MutationStatus;
+use mutator::mutate;
+mod run;
+mod store;
+
+#[derive(Parser, Default)]
+#[command(
+    about = "Bitcoin Core Mutations",
+    long_about = "Runs mutation testing on a local Bitcoin Core checkout, without a server or workers."
+)]
+struct Args {
+    #[clap(
+        short,
+        long,
+        help = "Files to mutate (defaults to the changes since --base)"
+    )]
+    files: Vec<String>,
+    #[clap(
+        long,
+        help = "Mutate the lines added since this ref",
+        default_value = "master"
+    )]
+    base: String,
+    #[clap(long, help = "Also mutate the Python test framework")]
+    python: bool,
+    #[clap(short, long, help = "Path to Bitcoin Core", default_value = ".")]
+    path: String,
+    #[clap(
+        long,
+        help = "SQLite database holding the state of the run",
+        default_value = "sqlite://bcm-local.db"
+    )]
+    db: String,
+    #[clap(
+        long,
+        help = "Directory of the worktrees",
+        default_value = "/tmp/bcm-worktrees"
+    )]
+    worktrees: String,
+    #[clap(
+        short,
+        long,
+        help = "Mutations tested in parallel",
+        default_value = "2"
+    )]
+    jobs: usize,
+    #[clap(
+        long,
+        help = "Command run once in each new worktree",
+        default_value = "./autogen.sh && ./configure --disable-fuzz --enable-fuzz-binary=no --with-gui=no --disable-zmq --disable-bench"
+    )]
+    setup_cmd: String,
+    #[clap(short, long, help = "Build command", default_value = "make -j$(nproc)")]
+    build_cmd: String,
+    #[clap(
+        short,
+        long,
+        help = "Test command",
+        default_value = "make check -j$(nproc) && python3 -u test/functional/test_runner.py -j$(expr $(nproc) + 4) -F"
+    )]
+    test_cmd: String,
+    #[clap(long, help = "Timeout (seconds)", default_value = "1800")]
+    timeout: u64,
+}
+
+#[actix_web::main]
+async fn main() {
+    let args = Args::parse();
+
+    std::env::set_current_dir(&args.path).expect("Failed to open Bitcoin Core");
+
+    ctrlc::set_handler(move || {
+        println!("Interrupted, run again with the same --db to resume.");
+        std::process::exit(130);
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    let pool = match store::open(&args.db).await {
+        Ok(pool) => pool,
+        Err(e) => panic!("Failed to open database: {}", e),
+    };
+
+    if store::count(&pool).await.unwrap() == 0 {
+        let changes = mutate::uncommitted_changes(".").expect("Failed to read git status");
+        if !changes.is_empty() {
+            eprintln!(
+                "Uncommitted changes to {} would be left out of the worktrees, commit or stash them first.",
+                changes.join(", ")
+            );
+            std::process::exit(1);
+        }
+
+        let mutations = if args.files.is_empty() {
+            println!("Generating mutations for the changes since {}", args.base);
+            mutate::generate_mutations_from_diff(&args.base, None, args.python)
+        } else {
+            println!("Generating mutations for files");
+            mutate::generate_mutations_from_files(&args.files)
+        };
+        println!("{} mutations found", mutations.len());
+
+        store::insert(&pool, mutations).await.unwrap();
+    } else {
+        println!("Resuming run from {}", args.db);
+    }
+
+    let pending = store::pending(&pool).await.unwrap();
+    println!("{} mutations to test", pending.len());
+
+    let r = run::execute_mutations(
+        &pool,
+        pending,
+        run::Pipeline {
+            repo: ".".to_string(),
+            worktrees: args.worktrees,
+            jobs: args.jobs,
+            setup_cmd: args.setup_cmd,
+            build_cmd: args.build_cmd,
+            test_cmd: args.test_cmd,
+            timeout: args.timeout,
+        },
+    )
+    .await;
+
+    if r.is_err() {
+        panic!("Local run failed with error: {}", r.unwrap_err());
+    }
+
+    print_summary(&pool).await;
+}
+
+async fn print_summary(pool: &sqlx::SqlitePool) {
+    let summary = store::summary(pool).await.unwrap();
+    let count = |status: MutationStatus| {
+        summary
+            .iter()
+            .find(|(s, _)| *s == status.to_string())
+            .map(|(_, c)| *c)
+            .unwrap_or(0)
+    };
+
+    let survivors = store::survivors(pool).await.unwrap();
+    if !survivors.is_empty() {
+        println!("\nSurviving mutations:");
+        for (file, line, mutator, patch) in survivors {
+            println!("{}:{} ({})", file, line, mutator.unwrap_or_default());
+            println!("{}", patch);
+        }
+    }
+
+    println!("\nSummary:");
+    for (status, count) in &summary {
+        println!("  {}: {}", status, count);
+    }
+
+    let killed = count(MutationStatus::Killed);
+    let survived = count(MutationStatus::NotKilled);
+    if killed + survived > 0 {
+        println!(
+            "  Mutation score: {:.1}%",
+            100.0 * killed as f64 / (killed + survived) as f64
+        );
+    }
+}
@@ -0,0 +1,242 @@
+use std::sync::{mpsc, Arc, Mutex};
+
+use common::{Mutation, MutationResult};
+use sqlx::sqlite::SqlitePool;
+
+use crate::store;
+
+pub struct Pipeline {
+    /// Checkout the worktrees are added to.
+    pub repo: String,
+    pub worktrees: String,
+    pub jobs: usize,
+    pub setup_cmd: String,
+    pub build_cmd: String,
+    pub test_cmd: String,
+    pub timeout: u64,
+}
+
+/// Runs `mutations` across `pipeline.jobs` git worktrees, each one building
+/// and testing a single mutant at a time. Results are stored as soon as they
+/// come in so an interrupted run only loses the mutants in flight.
+pub async fn execute_mutations(
+    pool: &SqlitePool,
+    mutations: Vec<Mutation>,
+    pipeline: Pipeline,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let total = mutations.len();
+    let pipeline = Arc::new(pipeline);
+
+    let (work_tx, work_rx) = mpsc::channel::<Mutation>();
+    let (result_tx, result_rx) = mpsc::channel();
+    let work_rx = Arc::new(Mutex::new(work_rx));
+
+    for i in 0..pipeline.jobs.min(total) {
+        let path = prepare_worktree(&pipeline, i)?;
+        let pipeline = pipeline.clone();
+        let work_rx = work_rx.clone();
+        let result_tx = result_tx.clone();
+
+        std::thread::spawn(move || loop {
+            let mutation = match work_rx.lock().unwrap().recv() {
+                Ok(mutation) => mutation,
+                Err(_) => break,
+            };
+
+            let start_time = chrono::Utc::now().timestamp();
+            let result = execute_mutation(&mutation, &path, &pipeline);
+            if result_tx.send((mutation, start_time, result)).is_err() {
+                break;
+            }
+        });
+    }
+    drop(result_tx);
+
+    for mutation in mutations {
+        work_tx.send(mutation)?;
+    }
+    drop(work_tx);
+
+    for (i, (mutation, start_time, result)) in result_rx.iter().enumerate() {
+        match result {
+            Ok(result) => {
+                println!(
+                    "[{}/{}] {}:{} {:?}",
+                    i + 1,
+                    total,
+                    mutation.file,
+                    mutation.line,
+                    result.status
+                );
+                store::save_result(pool, mutation.id, start_time, &result).await?;
+            }
+            Err(e) => println!(
+                "[{}/{}] {}:{} failed: {}",
+                i + 1,
+                total,
+                mutation.file,
+                mutation.line,
+                e
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+fn execute_mutation(
+    mutation: &Mutation,
+    path: &str,
+    pipeline: &Pipeline,
+) -> Result<MutationResult, String> {
+    // Only tracked files are restored, build outputs are kept around so
+    // the next mutant builds incrementally.
+    let base_commit = mutation.base_commit.as_deref().unwrap_or("HEAD");
+    let status = std::process::Command::new("git")
+        .current_dir(path)
+        .args(["checkout", "--force", "--detach", base_commit])
+        .status()
+        .map_err(|e| e.to_string())?;
+    if !status.success() {
+        return Err(format!("failed to check out {}", base_commit));
+    }
+
+    worker::run::test_mutation(
+        mutation,
+        path,
+        &pipeline.build_cmd,
+        &pipeline.test_cmd,
+        pipeline.timeout,
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// Creates (or reuses) the i-th worktree and returns its path.
+fn prepare_worktree(pipeline: &Pipeline, i: usize) -> Result<String, Box<dyn std::error::Error>> {
+    let path = format!("{}/{}", pipeline.worktrees, i);
+    if std::path::Path::new(&path).exists() {
+        return Ok(path);
+    }
+
+    println!("Creating worktree {}", path);
+    let status = std::process::Command::new("git")
+        .current_dir(&pipeline.repo)
+        .args(["worktree", "add", "--detach", &path, "HEAD"])
+        .status()?;
+    if !status.success() {
+        return Err(format!("failed to create worktree {}", path).into());
+    }
+
+    let status = std::process::Command::new("bash")
+        .current_dir(&path)
+        .arg("-c")
+        .arg(&pipeline.setup_cmd)
+        .status()?;
+    if !status.success() {
+        std::fs::remove_dir_all(&path)?;
+        return Err(format!("failed to set up worktree {}", path).into());
+    }
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::MutationStatus;
+    use mutator::mutate;
+
+    fn git(repo: &std::path::Path, args: &[&str]) {
+        let status = std::process::Command::new("git")
+            .current_dir(repo)
+            .args(["-c", "user.name=bcm", "-c", "user.email=bcm@localhost"])
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    fn mutation(md5: &str, from: &str, to: &str) -> Mutation {
+        Mutation {
+            patch_md5: md5.to_string(),
+            file: "src/add.cpp".to_string(),
+            line: 3,
+            patch: format!(
+                "--- a/src/add.cpp\n+++ b/src/add.cpp\n@@ -3 +3 @@\n-{}\n+{}\n",
+                from, to
+            ),
+            status: MutationStatus::Pending.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_mutants_are_tested_in_worktrees() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("repo");
+        std::fs::create_dir_all(repo.join("src")).unwrap();
+        git(&repo, &["init", "-q"]);
+        std::fs::write(
+            repo.join("src/add.cpp"),
+            "int add(int a, int b)\n{\n    return a + b;\n}\n",
+        )
+        .unwrap();
+        git(&repo, &["add", "."]);
+        git(&repo, &["commit", "-qm", "add"]);
+
+        let repo_path = repo.to_str().unwrap();
+        assert!(mutate::uncommitted_changes(repo_path).unwrap().is_empty());
+        std::fs::write(repo.join("src/add.cpp"), "int add(int a, int b);\n").unwrap();
+        assert_eq!(
+            mutate::uncommitted_changes(repo_path).unwrap(),
+            ["src/add.cpp"]
+        );
+        git(&repo, &["checkout", "--", "."]);
+
+        let db = format!("sqlite://{}", dir.path().join("run.db").display());
+        let pool = store::open(&db).await.unwrap();
+        store::insert(
+            &pool,
+            vec![
+                mutation("killed", "    return a + b;", "    return a - b;"),
+                // Comments are not seen by the test below.
+                mutation("survived", "    return a + b;", "    return a + b; // sum"),
+                mutation("stale", "    return a * b;", "    return a / b;"),
+            ],
+        )
+        .await
+        .unwrap();
+
+        let pending = store::pending(&pool).await.unwrap();
+        execute_mutations(
+            &pool,
+            pending,
+            Pipeline {
+                repo: repo_path.to_string(),
+                worktrees: dir.path().join("worktrees").display().to_string(),
+                jobs: 2,
+                setup_cmd: "true".to_string(),
+                build_cmd: "true".to_string(),
+                test_cmd: "grep -q 'a + b' src/add.cpp".to_string(),
+                timeout: 60,
+            },
+        )
+        .await
+        .unwrap();
+
+        let statuses: Vec<(String, String)> =
+            sqlx::query_as("SELECT patch_md5, status FROM mutations ORDER BY patch_md5")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            statuses,
+            [
+                ("killed".to_string(), "Killed".to_string()),
+                ("stale".to_string(), "Stale".to_string()),
+                ("survived".to_string(), "NotKilled".to_string()),
+            ]
+        );
+        assert!(store::pending(&pool).await.unwrap().is_empty());
+    }
+}
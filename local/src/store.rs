@@ -0,0 +1,230 @@
+use common::{Mutation, MutationResult, MutationStatus};
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+use sqlx::Row;
+use std::str::FromStr;
+
+/// The server's schema, so a local run stores mutations the way the server
+/// does.
+static MIGRATOR: Migrator = sqlx::migrate!("../server/migrations");
+
+pub async fn open(db: &str) -> Result<SqlitePool, Box<dyn std::error::Error>> {
+    let options = SqliteConnectOptions::from_str(db)?.create_if_missing(true);
+    let pool = SqlitePool::connect_with(options).await?;
+
+    // Runs started before the schema was shared created their own table.
+    let tables: Vec<String> = sqlx::query_scalar(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name IN ('mutations', '_sqlx_migrations')",
+    )
+    .fetch_all(&pool)
+    .await?;
+    if tables == ["mutations"] {
+        return Err(format!(
+            "{} was created by an older version, remove it or use another --db",
+            db
+        )
+        .into());
+    }
+
+    MIGRATOR.run(&pool).await?;
+
+    Ok(pool)
+}
+
+pub async fn count(pool: &SqlitePool) -> Result<i64, sqlx::Error> {
+    sqlx::query("SELECT COUNT(*) FROM mutations")
+        .fetch_one(pool)
+        .await?
+        .try_get(0)
+}
+
+/// Stores new mutations, skipping the patches already known.
+pub async fn insert(pool: &SqlitePool, mutations: Vec<Mutation>) -> Result<(), sqlx::Error> {
+    for mutation in mutations {
+        sqlx::query(
+            "INSERT INTO mutations (patch_md5, file, line, patch, branch, pr_number, status, fingerprint, mutator, base_commit)
+            SELECT ?, ?, ?, ?, ?, ?, ?, ?, ?, ? WHERE NOT EXISTS (SELECT 1 FROM mutations WHERE patch_md5 = ?)",
+        )
+        .bind(mutation.patch_md5.clone())
+        .bind(mutation.file)
+        .bind(mutation.line)
+        .bind(mutation.patch)
+        .bind(mutation.branch)
+        .bind(mutation.pr_number)
+        .bind(mutation.status)
+        .bind(mutation.fingerprint)
+        .bind(mutation.mutator)
+        .bind(mutation.base_commit)
+        .bind(mutation.patch_md5)
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Mutations that have not produced a result yet, including the ones that
+/// were running when a previous run was interrupted.
+pub async fn pending(pool: &SqlitePool) -> Result<Vec<Mutation>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT id, patch_md5, file, line, patch, branch, pr_number, status, fingerprint, mutator, base_commit FROM mutations WHERE status = ? ORDER BY file, line",
+    )
+    .bind(MutationStatus::Pending.to_string())
+    .fetch_all(pool)
+    .await?;
+
+    rows.into_iter()
+        .map(|row| {
+            Ok(Mutation {
+                id: row.try_get("id")?,
+                patch_md5: row.try_get("patch_md5")?,
+                file: row.try_get("file")?,
+                line: row.try_get("line")?,
+                patch: row.try_get("patch")?,
+                branch: row.try_get("branch")?,
+                pr_number: row.try_get("pr_number")?,
+                status: row.try_get("status")?,
+                start_time: None,
+                end_time: None,
+                stderr: None,
+                stdout: None,
+                fingerprint: row.try_get("fingerprint")?,
+                mutator: row.try_get("mutator")?,
+                base_commit: row.try_get("base_commit")?,
+                retired_reason: None,
+                worker: None,
+                worker_id: None,
+                lease_expires_at: None,
+                attempts: 0,
+                campaign_id: None,
+                task_state: None,
+                assignee: None,
+                priority: 0,
+                requires: vec![],
+            })
+        })
+        .collect()
+}
+
+pub async fn save_result(
+    pool: &SqlitePool,
+    id: i64,
+    start_time: i64,
+    result: &MutationResult,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE mutations SET status = ?, stdout = ?, stderr = ?, start_time = ?, end_time = ? WHERE id = ?",
+    )
+    .bind(result.status.to_string())
+    .bind(&result.stdout)
+    .bind(&result.stderr)
+    .bind(start_time)
+    .bind(chrono::Utc::now().timestamp())
+    .bind(id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Number of mutations per status.
+pub async fn summary(pool: &SqlitePool) -> Result<Vec<(String, i64)>, sqlx::Error> {
+    let rows = sqlx::query("SELECT status, COUNT(*) AS count FROM mutations GROUP BY status")
+        .fetch_all(pool)
+        .await?;
+
+    rows.into_iter()
+        .map(|row| Ok((row.try_get("status")?, row.try_get("count")?)))
+        .collect()
+}
+
+/// `(file, line, mutator, patch)` of every mutation that survived.
+pub async fn survivors(
+    pool: &SqlitePool,
+) -> Result<Vec<(String, i64, Option<String>, String)>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT file, line, mutator, patch FROM mutations WHERE status = ? ORDER BY file, line",
+    )
+    .bind(MutationStatus::NotKilled.to_string())
+    .fetch_all(pool)
+    .await?;
+
+    rows.into_iter()
+        .map(|row| {
+            Ok((
+                row.try_get("file")?,
+                row.try_get("line")?,
+                row.try_get("mutator")?,
+                row.try_get("patch")?,
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mutation(md5: &str, line: i64) -> Mutation {
+        Mutation {
+            patch_md5: md5.to_string(),
+            file: "src/validation.cpp".to_string(),
+            line,
+            status: MutationStatus::Pending.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_run_resumes_with_the_mutations_left() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = format!("sqlite://{}", dir.path().join("run.db").display());
+
+        let pool = open(&db).await.unwrap();
+        insert(
+            &pool,
+            vec![mutation("a", 1), mutation("b", 2), mutation("a", 1)],
+        )
+        .await
+        .unwrap();
+        assert_eq!(count(&pool).await.unwrap(), 2);
+
+        let first = &pending(&pool).await.unwrap()[0];
+        let result = MutationResult {
+            mutation_id: first.patch_md5.clone(),
+            status: MutationStatus::Killed,
+            stdout: None,
+            stderr: None,
+            exit_code: Some(1),
+        };
+        save_result(&pool, first.id, 0, &result).await.unwrap();
+        pool.close().await;
+
+        let pool = open(&db).await.unwrap();
+        let left = pending(&pool).await.unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].patch_md5, "b");
+        assert_eq!(
+            summary(&pool).await.unwrap(),
+            [("Killed".to_string(), 1), ("Pending".to_string(), 1)]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_databases_of_older_runs_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = format!("sqlite://{}", dir.path().join("old.db").display());
+
+        let options = SqliteConnectOptions::from_str(&db)
+            .unwrap()
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await.unwrap();
+        sqlx::query("CREATE TABLE mutations (id INTEGER NOT NULL PRIMARY KEY)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+
+        assert!(open(&db).await.is_err());
+    }
+}
@@ -1,7 +1,6 @@
 use clap::Parser;
-use common::Mutation;
-pub mod mutate;
-pub mod mutators;
+use common::{Campaign, Mutation, NewCampaign};
+use mutator::mutate;
 
 #[derive(Parser, Default)]
 #[command(
@@ -17,20 +16,61 @@ struct Args {
     server: String,
     #[clap(long, help = "Token to use for authentication")]
     token: String,
+    #[clap(long, help = "Also mutate the Python test framework")]
+    python: bool,
     #[clap(long, help = "Debug mode")]
     debug: bool,
+    #[clap(long, help = "Move pending mutations onto this commit")]
+    rebase: Option<String>,
+    #[clap(
+        long,
+        help = "Context lines that may mismatch when rebasing",
+        default_value = "2"
+    )]
+    fuzz: usize,
+    #[clap(
+        long,
+        help = "Campaign to add the mutations to, created if needed (defaults to pr-<number> or files-<hash of the files>)"
+    )]
+    campaign: Option<String>,
+    #[clap(
+        long,
+        help = "Priority of a new campaign, higher campaigns are worked on first (defaults to 0)"
+    )]
+    priority: Option<i64>,
+    #[clap(
+        long = "require",
+        help = "Capabilities workers need to run the mutations of a new campaign (e.g. tsan, wallet, qt, fuzz)"
+    )]
+    requires: Vec<String>,
 }
 
 #[actix_web::main]
 async fn main() {
     let args = Args::parse();
 
+    if let Some(base_commit) = args.rebase {
+        let r = rebase_pending_mutations(
+            &args.server,
+            &base_commit,
+            args.fuzz,
+            &args.token,
+            args.campaign.as_deref(),
+        )
+        .await;
+        if r.is_err() {
+            panic!("Rebase failed with error: {}", r.unwrap_err());
+        }
+
+        return;
+    }
+
     let files = args.files;
 
     let mutations = {
         if let Some(pr) = args.pr {
             println!("Generating mutations for PR #{}", pr);
-            let muts = mutate::generate_mutations_from_pr(pr);
+            let muts = mutate::generate_mutations_from_pr(pr, args.python);
             println!("{} mutations found", muts.len());
             muts
         } else {
@@ -55,29 +95,181 @@ async fn main() {
         return;
     }
 
-    let r = send_mutations(args.server, mutations, &args.token).await;
+    let changes = mutate::uncommitted_changes(".").expect("Failed to read git status");
+    if !changes.is_empty() {
+        eprintln!(
+            "Uncommitted changes to {} would not apply at the base commit, commit or stash them first.",
+            changes.join(", ")
+        );
+        std::process::exit(1);
+    }
+
+    // The default campaigns do not depend on the commit, so uploading again
+    // for a later one re-anchors the mutations that have not run yet.
+    let base_commit = mutations.first().and_then(|m| m.base_commit.clone());
+    let requires = (!args.requires.is_empty()).then_some(args.requires);
+    let campaign = match args.pr {
+        Some(pr) => NewCampaign {
+            name: args.campaign.unwrap_or_else(|| format!("pr-{}", pr)),
+            base_commit,
+            source: "pr".to_string(),
+            source_ref: Some(pr.to_string()),
+            priority: args.priority,
+            requires,
+        },
+        None => NewCampaign {
+            name: args.campaign.unwrap_or_else(|| files_campaign(&files)),
+            base_commit,
+            source: "files".to_string(),
+            source_ref: Some(file_list(&files)),
+            priority: args.priority,
+            requires,
+        },
+    };
+
+    let r = send_mutations(args.server, campaign, mutations, &args.token).await;
 
     if r.is_err() {
         panic!("Mutator failed with error: {}", r.unwrap_err());
     }
 }
 
+/// The files of a run, sorted so the same set is always written the same way.
+fn file_list(files: &[String]) -> String {
+    let mut files = files.to_vec();
+    files.sort();
+    files.dedup();
+    files.join(",")
+}
+
+/// The default campaign of a set of files, named after it like `pr-<number>`
+/// is after a PR, so runs of other files are kept apart.
+fn files_campaign(files: &[String]) -> String {
+    let hash = hex::encode(md5::compute(file_list(files)).to_vec());
+    format!("files-{}", &hash[..8])
+}
+
 pub async fn send_mutations(
     server: String,
-    mutations: Vec<Mutation>,
+    campaign: NewCampaign,
+    mut mutations: Vec<Mutation>,
     token: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let client = reqwest::Client::new();
 
+    let res = client
+        .post(format!("{}/campaigns", server))
+        .body(serde_json::to_string(&campaign)?)
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}", token))
+        .send()
+        .await?
+        .error_for_status()?;
+    let campaign = serde_json::from_str::<Campaign>(&res.text().await?)?;
+    println!("Using campaign {} ({})", campaign.name, campaign.id);
+
+    for m in mutations.iter_mut() {
+        m.campaign_id = Some(campaign.id);
+    }
+
     let body = serde_json::to_string(&mutations)?;
     let res = client
-        .post(&format!("{}/mutations", server))
+        .post(format!("{}/mutations", server))
         .body(body)
         .header("Content-Type", "application/json")
-        .header("Authorization", token)
+        .header("Authorization", format!("Bearer {}", token))
         .send()
         .await?;
 
     println!("Sent mutation: {}", res.status());
     Ok(())
 }
+
+pub async fn rebase_pending_mutations(
+    server: &str,
+    base_commit: &str,
+    fuzz: usize,
+    token: &str,
+    campaign: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = reqwest::Client::new();
+
+    let mut url = format!("{}/mutations/Pending?patch=true&sort=id&order=asc", server);
+    if let Some(name) = campaign {
+        let res = client
+            .get(format!("{}/campaigns", server))
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await?;
+        let campaigns = serde_json::from_str::<Vec<Campaign>>(&res.text().await?)?;
+        let campaign = campaigns
+            .iter()
+            .find(|c| c.name == name)
+            .ok_or(format!("Unknown campaign: {}", name))?;
+        url = format!("{}&campaign={}", url, campaign.id);
+    }
+
+    // Listings are paginated, fetch pages until a short one.
+    let limit = 1000;
+    let mut mutations = vec![];
+    loop {
+        let res = client
+            .get(format!("{}&limit={}&offset={}", url, limit, mutations.len()))
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await?
+            .error_for_status()?;
+        let page = serde_json::from_str::<Vec<Mutation>>(&res.text().await?)?;
+        let done = page.len() < limit;
+        mutations.extend(page);
+        if done {
+            break;
+        }
+    }
+    println!("{} pending mutations", mutations.len());
+
+    let result = mutate::rebase::rebase_mutations(&mutations, base_commit, fuzz);
+    println!(
+        "{} mutations rebased, {} retired",
+        result.rebased.len(),
+        result.retired.len()
+    );
+
+    let res = client
+        .post(format!("{}/rebase", server))
+        .body(serde_json::to_string(&result)?)
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}", token))
+        .send()
+        .await?;
+
+    println!("Sent rebase: {}", res.status());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_files_campaigns_are_named_after_the_file_set() {
+        let files = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let wallet = files_campaign(&files(&["src/wallet/spend.cpp", "src/wallet/coins.cpp"]));
+        assert!(wallet.starts_with("files-"));
+        assert_eq!(
+            wallet,
+            files_campaign(&files(&["src/wallet/coins.cpp", "src/wallet/spend.cpp"]))
+        );
+        assert_ne!(wallet, files_campaign(&files(&["src/net.cpp"])));
+        assert_eq!(
+            file_list(&files(&["src/b.cpp", "src/a.cpp", "src/b.cpp"])),
+            "src/a.cpp,src/b.cpp"
+        );
+    }
+}
@@ -3,6 +3,7 @@ use std::str::FromStr;
 use crate::mutators::{
     execution_flow::ExecutionFlowMutator,
     operator::{BoolAritmeticMutator, BoolOperatorMutator, IncDecMutator, OperatorMutator},
+    python::{PythonBoolOperatorMutator, PythonIndexMutator, PythonOperatorMutator},
     std_algorithm::StdAlgorithmMutator,
     Mutator, MutatorContext,
 };
@@ -10,6 +11,8 @@ use common::{Mutation, MutationStatus};
 use lazy_static::lazy_static;
 use unidiff;
 
+pub mod rebase;
+
 lazy_static! {
     static ref MUTATORS: Vec<Box<dyn Mutator + Sync>> = vec![
         Box::new(OperatorMutator::default()),
@@ -19,61 +22,173 @@ lazy_static! {
         Box::new(BoolAritmeticMutator::default()),
         Box::new(IncDecMutator::default()),
     ];
+    static ref PYTHON_MUTATORS: Vec<Box<dyn Mutator + Sync>> = vec![
+        Box::new(PythonOperatorMutator::default()),
+        Box::new(PythonBoolOperatorMutator::default()),
+        Box::new(PythonIndexMutator::default()),
+    ];
 }
 
-pub fn generate_mutations(lines: &[&str]) -> Vec<(usize, String)> {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Language {
+    Cpp,
+    Python,
+}
+
+impl Language {
+    pub fn from_file(file: &str) -> Language {
+        if file.ends_with(".py") {
+            Language::Python
+        } else {
+            Language::Cpp
+        }
+    }
+
+    fn mutators(&self) -> &'static [Box<dyn Mutator + Sync>] {
+        match self {
+            Language::Cpp => &MUTATORS,
+            Language::Python => &PYTHON_MUTATORS,
+        }
+    }
+
+    /// Whether each line is part of a multi-line string, docstrings mostly,
+    /// whose prose would only give equivalent mutants. Lines opening or
+    /// closing one count too.
+    fn in_strings(&self, lines: &[&str]) -> Vec<bool> {
+        if *self != Language::Python {
+            return vec![false; lines.len()];
+        }
+
+        let mut open: Option<&str> = None;
+        lines
+            .iter()
+            .map(|line| {
+                let mut in_string = open.is_some();
+                let mut rest = *line;
+                loop {
+                    let next = match open {
+                        Some(quote) => rest.find(quote).map(|i| (i, quote)),
+                        None => ["\"\"\"", "'''"]
+                            .iter()
+                            .filter_map(|quote| rest.find(quote).map(|i| (i, *quote)))
+                            .min_by_key(|(i, _)| *i),
+                    };
+                    match next {
+                        Some((i, quote)) => {
+                            in_string = true;
+                            open = if open.is_some() { None } else { Some(quote) };
+                            rest = &rest[i + quote.len()..];
+                        }
+                        None => break,
+                    }
+                }
+                in_string
+            })
+            .collect()
+    }
+
+    fn is_ignored(&self, line: &str) -> bool {
+        let trimmed = line.trim_start();
+        match self {
+            Language::Cpp => {
+                trimmed.starts_with("//")
+                    || trimmed.starts_with('*')
+                    || trimmed.starts_with("assert")
+                    || trimmed.starts_with("/*")
+                    || trimmed.starts_with("LogPrint")
+            }
+            Language::Python => {
+                trimmed.starts_with('#')
+                    || trimmed.starts_with("assert")
+                    || trimmed.starts_with("import ")
+                    || trimmed.starts_with("from ")
+                    || trimmed.starts_with("self.log")
+                    || trimmed.starts_with("logger.")
+            }
+        }
+    }
+}
+
+pub struct LineMutation {
+    pub line: usize,
+    pub mutator: &'static str,
+    pub content: String,
+}
+
+pub fn generate_mutations(file: &str, lines: &[&str]) -> Vec<LineMutation> {
     let mut mutations = vec![];
 
+    let in_strings = Language::from_file(file).in_strings(lines);
     for (i, line) in lines.iter().enumerate() {
-        mutations.extend(mutate_line(i, line));
+        if !in_strings[i] {
+            mutations.extend(mutate_line(file, i, line));
+        }
     }
 
     mutations
 }
 
-pub fn mutate_line(number: usize, line: &str) -> Vec<(usize, String)> {
-    let trimmed = line.trim_start();
-    if trimmed.starts_with("//")
-        || trimmed.starts_with('*')
-        || trimmed.starts_with("assert")
-        || trimmed.starts_with("/*")
-        || trimmed.starts_with("LogPrint")
-    {
+pub fn mutate_line(file: &str, number: usize, line: &str) -> Vec<LineMutation> {
+    let language = Language::from_file(file);
+    if language.is_ignored(line) {
         return vec![];
     }
 
     let mut mutations = vec![];
 
-    for m in MUTATORS.iter() {
+    for m in language.mutators() {
         let ctx = MutatorContext {
-            file: "".to_string(),
+            file: file.to_string(),
             line: number,
             line_content: line.to_string(),
         };
 
         let muts = m.mutate(&ctx);
-        mutations.extend(muts.into_iter().map(|m| (number, m)));
+        mutations.extend(muts.into_iter().map(|content| LineMutation {
+            line: number,
+            mutator: m.name(),
+            content,
+        }));
     }
 
     mutations
 }
 
 pub fn generate_mutations_from_files(files: &Vec<String>) -> Vec<Mutation> {
+    let base_commit = current_commit();
+    println!("Base commit: {}", base_commit);
+
     let mut mutations = vec![];
     for file in files {
         println!("File: {}", file);
 
-        let content = std::fs::read_to_string(&file).unwrap();
+        let content = std::fs::read_to_string(file).unwrap();
         let lines = content.split('\n').collect::<Vec<&str>>();
 
-        let muts = generate_mutations(&lines);
+        let muts = generate_mutations(file, &lines);
 
         println!("{} mutations found", muts.len());
         println!("Generating patches...");
 
-        for (line, mutation) in muts {
-            let patch = create_patch(&content, line, &mutation);
-            let m = create_mutation(file, &patch, line, Some("master".to_string()), None);
+        for mutation in muts {
+            let patch = create_patch(&content, mutation.line, &mutation.content);
+            let fingerprint = fingerprint(
+                file,
+                &lines,
+                mutation.line,
+                &mutation.content,
+                mutation.mutator,
+            );
+            let mut m = create_mutation(
+                file,
+                &patch,
+                mutation.line,
+                Some("master".to_string()),
+                None,
+            );
+            m.fingerprint = Some(fingerprint);
+            m.mutator = Some(mutation.mutator.to_string());
+            m.base_commit = Some(base_commit.clone());
             mutations.push(m);
         }
     }
@@ -94,9 +209,89 @@ fn create_patch(original_content: &str, line: usize, mutation: &str) -> String {
     }
 
     let new_content = new_lines.join("\n");
-    let patch = diffy::create_patch(original_content, &new_content);
+    let patch = diffy::create_patch(original_content, &new_content).to_string();
+
+    // Like `git diff`, show the enclosing function in the hunk header so a
+    // patch can be understood (and re-anchored) without the whole file.
+    match enclosing_function(&lines, line) {
+        Some((_, function)) => patch
+            .lines()
+            .map(|l| {
+                if l.starts_with("@@") {
+                    format!("{} {}\n", l, function.trim())
+                } else {
+                    format!("{}\n", l)
+                }
+            })
+            .collect(),
+        None => patch,
+    }
+}
+
+/// Finds the function definition enclosing `line`, using the same heuristic
+/// as git's default hunk header: the closest preceding line that starts at
+/// column 0 with an identifier and opens a parameter list. Python methods are
+/// indented, so any `def` line also counts.
+/// Returns the line index of the definition and its content.
+fn enclosing_function<'a>(lines: &[&'a str], line: usize) -> Option<(usize, &'a str)> {
+    for i in (0..=line.min(lines.len().saturating_sub(1))).rev() {
+        let l = lines[i];
+        let starts_with_ident = l
+            .chars()
+            .next()
+            .map(|c| c.is_alphabetic() || c == '_')
+            .unwrap_or(false);
+
+        if (starts_with_ident && l.contains('(') && !l.trim_end().ends_with(';'))
+            || l.trim_start().starts_with("def ")
+        {
+            return Some((i, l));
+        }
+    }
 
-    patch.to_string()
+    None
+}
+
+fn normalize_line(line: &str) -> String {
+    line.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// Builds an identity for a mutant that does not depend on where the
+/// mutated line sits in the file, so the same mutant generated against a
+/// later upstream commit maps to the same fingerprint.
+///
+/// Identical lines within the same function are told apart by their
+/// occurrence index, which only changes when such a line is added or
+/// removed above it in that function.
+pub fn fingerprint(
+    file: &str,
+    lines: &[&str],
+    line: usize,
+    mutated: &str,
+    mutator: &str,
+) -> String {
+    let original = normalize_line(lines[line]);
+    let (function_start, function) = match enclosing_function(lines, line) {
+        Some((start, function)) => (start, normalize_line(function)),
+        None => (0, String::new()),
+    };
+
+    let occurrence = lines[function_start..line]
+        .iter()
+        .filter(|l| normalize_line(l) == original)
+        .count();
+
+    let identity = [
+        file.to_string(),
+        function,
+        original,
+        occurrence.to_string(),
+        normalize_line(mutated),
+        mutator.to_string(),
+    ]
+    .join("\n");
+
+    hex::encode(md5::compute(identity).to_vec())
 }
 
 fn create_mutation(
@@ -122,10 +317,52 @@ fn create_mutation(
         end_time: None,
         stderr: None,
         stdout: None,
+        fingerprint: None,
+        mutator: None,
+        base_commit: None,
+        retired_reason: None,
+        worker: None,
+        worker_id: None,
+        lease_expires_at: None,
+        attempts: 0,
+        campaign_id: None,
+        task_state: None,
+        assignee: None,
+        priority: 0,
+        requires: vec![],
     }
 }
 
-pub fn generate_mutations_from_pr(pr_number: i64) -> Vec<Mutation> {
+/// Returns the SHA of the commit checked out in the working directory.
+fn current_commit() -> String {
+    let cmd = std::process::Command::new("git")
+        .arg("rev-parse")
+        .arg("HEAD")
+        .output()
+        .expect("failed to execute process");
+
+    String::from_utf8_lossy(&cmd.stdout).trim().to_string()
+}
+
+/// Tracked files of `repo` with uncommitted changes. Mutations are generated
+/// from the working tree but applied on the commit, so mutants of those
+/// changes would not apply.
+pub fn uncommitted_changes(repo: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let output = std::process::Command::new("git")
+        .current_dir(repo)
+        .args(["status", "--porcelain", "--untracked-files=no"])
+        .output()?;
+    if !output.status.success() {
+        return Err(format!("{} is not a git checkout", repo).into());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| line[3..].to_string())
+        .collect())
+}
+
+pub fn generate_mutations_from_pr(pr_number: i64, python: bool) -> Vec<Mutation> {
     let cmd = std::process::Command::new("gh")
         .arg("pr")
         .arg("checkout")
@@ -137,17 +374,28 @@ pub fn generate_mutations_from_pr(pr_number: i64) -> Vec<Mutation> {
     let output = output.trim();
     println!("Output: {}", output);
 
-    std::process::Command::new("git")
-        .arg("pull")
-        .arg("origin")
-        .arg("master")
-        .arg("--rebase")
-        .output()
-        .expect("failed to execute process");
+    // Mutations are pinned to the PR head as pushed, so workers can fetch
+    // the exact same commit.
+    generate_mutations_from_diff("master", Some(pr_number), python)
+}
+
+/// Python files of the functional test framework, mutated when Python
+/// mutations are enabled. Their bugs can hide consensus bugs.
+const PYTHON_TEST_FRAMEWORK: &str = "test/functional/test_framework/";
+
+/// Generates mutations for the lines added on the current checkout since it
+/// forked from `base`.
+pub fn generate_mutations_from_diff(
+    base: &str,
+    pr_number: Option<i64>,
+    python: bool,
+) -> Vec<Mutation> {
+    let base_commit = current_commit();
+    println!("Base commit: {}", base_commit);
 
     let cmd = std::process::Command::new("git")
         .arg("diff")
-        .arg("master")
+        .arg(format!("{}...HEAD", base))
         .output()
         .expect("failed to execute process");
 
@@ -164,9 +412,10 @@ pub fn generate_mutations_from_pr(pr_number: i64) -> Vec<Mutation> {
         }
         let file_path = file_path[2..].to_string();
         if file_path.ends_with(".py") {
-            continue;
-        }
-        if file_path.starts_with("test/")
+            if !python || !file_path.starts_with(PYTHON_TEST_FRAMEWORK) {
+                continue;
+            }
+        } else if file_path.starts_with("test/")
             || file_path.starts_with("src/test")
             || file_path.starts_with("src/qt/test")
             || file_path.starts_with("src/wallet/test")
@@ -178,15 +427,35 @@ pub fn generate_mutations_from_pr(pr_number: i64) -> Vec<Mutation> {
         // remove a/ and b/ from the path
         println!("File path: {}", file_path);
         let file_content = std::fs::read_to_string(&file_path).unwrap();
+        let file_lines = file_content.split('\n').collect::<Vec<&str>>();
+        let in_strings = Language::from_file(&file_path).in_strings(&file_lines);
         for hunk in patch {
             for line in hunk.lines() {
-                if line.is_added() {
-                    let muts = mutate_line(line.target_line_no.unwrap(), &line.value);
+                let in_string = line
+                    .target_line_no
+                    .is_some_and(|no| in_strings.get(no - 1) == Some(&true));
+                if line.is_added() && !in_string {
+                    let muts = mutate_line(&file_path, line.target_line_no.unwrap(), &line.value);
                     println!("Mutating line {}", line.value);
-                    for (line_no, mutation) in muts {
-                        println!("Mutation: {}", mutation);
-                        let patch = create_patch(&file_content, line_no-1, &mutation);
-                        let m = create_mutation(&file_path, &patch, line_no, None, Some(pr_number));
+                    for mutation in muts {
+                        println!("Mutation: {}", mutation.content);
+                        let line_no = mutation.line;
+                        let patch = create_patch(&file_content, line_no - 1, &mutation.content);
+                        let fingerprint = fingerprint(
+                            &file_path,
+                            &file_lines,
+                            line_no - 1,
+                            &mutation.content,
+                            mutation.mutator,
+                        );
+                        let branch = match pr_number {
+                            Some(_) => None,
+                            None => Some(base.to_string()),
+                        };
+                        let mut m = create_mutation(&file_path, &patch, line_no, branch, pr_number);
+                        m.fingerprint = Some(fingerprint);
+                        m.mutator = Some(mutation.mutator.to_string());
+                        m.base_commit = Some(base_commit.clone());
 
                         mutations.push(m);
                     }
@@ -212,7 +481,7 @@ pub fn generate_mutations_from_pr(pr_number: i64) -> Vec<Mutation> {
 //         for (to_be_mutated, expected_results) in data {
 //             let lines: Vec<&str> = vec![&to_be_mutated];
 //             let _success = true;
-//             let mutations = generate_mutations(&lines);
+//             let mutations = generate_mutations("", &lines);
 //             println!("Mutations: {:?}", mutations);
 //             println!("Expected: {:?}", expected_results);
 //             assert_eq!(mutations.len(), expected_results.len());
@@ -220,7 +489,7 @@ pub fn generate_mutations_from_pr(pr_number: i64) -> Vec<Mutation> {
 //             for expected_result in expected_results {
 //                 let mut find_result = false;
 //                 for mutation in &mutations {
-//                     if mutation.1 == expected_result {
+//                     if mutation.content == expected_result {
 //                         find_result = true;
 //                     }
 //                 }
@@ -229,3 +498,68 @@ pub fn generate_mutations_from_pr(pr_number: i64) -> Vec<Mutation> {
 //         }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::{fingerprint, generate_mutations, mutate_line, Language};
+
+    #[test]
+    fn test_fingerprint_is_stable_across_line_shifts() {
+        let before = "int Foo(int a)\n{\n    return a == 1;\n}\n";
+        let after = "// A new comment\n\nint Foo(int a)\n{\n    return a == 1;\n}\n";
+        let before = before.split('\n').collect::<Vec<&str>>();
+        let after = after.split('\n').collect::<Vec<&str>>();
+
+        let m_before = mutate_line("src/foo.cpp", 2, before[2]).remove(0);
+        let m_after = mutate_line("src/foo.cpp", 4, after[4]).remove(0);
+
+        assert_eq!(
+            fingerprint("src/foo.cpp", &before, 2, &m_before.content, m_before.mutator),
+            fingerprint("src/foo.cpp", &after, 4, &m_after.content, m_after.mutator)
+        );
+        assert_ne!(
+            fingerprint("src/foo.cpp", &before, 2, &m_before.content, m_before.mutator),
+            fingerprint("src/bar.cpp", &before, 2, &m_before.content, m_before.mutator)
+        );
+    }
+
+    #[test]
+    fn test_files_go_to_the_mutators_of_their_language() {
+        let names = |language: Language| -> Vec<&str> {
+            language.mutators().iter().map(|m| m.name()).collect()
+        };
+
+        let python = mutate_line(
+            "test/functional/test_framework/util.py",
+            0,
+            "    if a and b:",
+        );
+        assert!(!python.is_empty());
+        assert!(python
+            .iter()
+            .all(|m| names(Language::Python).contains(&m.mutator)));
+
+        let cpp = mutate_line("src/validation.cpp", 0, "    if (a && b) {");
+        assert!(!cpp.is_empty());
+        assert!(cpp
+            .iter()
+            .all(|m| names(Language::Cpp).contains(&m.mutator)));
+    }
+
+    #[test]
+    fn test_python_docstrings_are_not_mutated() {
+        let lines = [
+            "def check(a, b):",
+            "    \"\"\"Checks a and b.",
+            "",
+            "    Returns true if a or b is set.",
+            "    \"\"\"",
+            "    return a or b",
+            "    '''Same and more'''",
+        ];
+        let mutations = generate_mutations("test/functional/test_framework/util.py", &lines);
+
+        assert!(!mutations.is_empty());
+        assert!(mutations.iter().all(|m| m.line == 5));
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use common::{Mutation, RebaseResult, RetiredMutation};
+
+use super::{create_patch, fingerprint};
+
+/// Moves pending mutations onto `base_commit`. Mutations of pull requests
+/// are left alone as they are pinned to the PR head, not to master.
+pub fn rebase_mutations(mutations: &[Mutation], base_commit: &str, fuzz: usize) -> RebaseResult {
+    let mut contents: HashMap<String, Option<String>> = HashMap::new();
+    let mut rebased = vec![];
+    let mut retired = vec![];
+
+    for mutation in mutations {
+        if mutation.pr_number.is_some() || mutation.base_commit.as_deref() == Some(base_commit) {
+            continue;
+        }
+
+        let content = contents
+            .entry(mutation.file.clone())
+            .or_insert_with(|| file_at_commit(base_commit, &mutation.file));
+
+        let content = match content {
+            Some(content) => content,
+            None => {
+                retired.push(RetiredMutation {
+                    mutation_id: mutation.id,
+                    reason: format!("{} does not exist at {}", mutation.file, base_commit),
+                });
+                continue;
+            }
+        };
+
+        match rebase_mutation(mutation, content, base_commit, fuzz) {
+            Ok(m) => rebased.push(m),
+            Err(r) => {
+                println!("Retiring mutation {}: {}", r.mutation_id, r.reason);
+                retired.push(r);
+            }
+        }
+    }
+
+    RebaseResult {
+        base_commit: base_commit.to_string(),
+        rebased,
+        retired,
+    }
+}
+
+fn file_at_commit(commit: &str, file: &str) -> Option<String> {
+    let cmd = std::process::Command::new("git")
+        .arg("show")
+        .arg(format!("{}:{}", commit, file))
+        .output()
+        .expect("failed to execute process");
+
+    if !cmd.status.success() {
+        return None;
+    }
+
+    Some(String::from_utf8_lossy(&cmd.stdout).to_string())
+}
+
+/// Re-applies the patch of a pending mutation onto `content`, the file as of
+/// `base_commit`, and regenerates the mutation against it.
+///
+/// As with `patch --fuzz`, the hunk is first searched with its full context,
+/// then with up to `fuzz` context lines ignored at each end. The mutated line
+/// itself must always match exactly. When several positions match, the one
+/// closest to the original line wins.
+pub fn rebase_mutation(
+    mutation: &Mutation,
+    content: &str,
+    base_commit: &str,
+    fuzz: usize,
+) -> Result<Mutation, RetiredMutation> {
+    let retire = |reason: &str| RetiredMutation {
+        mutation_id: mutation.id,
+        reason: reason.to_string(),
+    };
+
+    let patch = diffy::Patch::from_str(&mutation.patch)
+        .map_err(|e| retire(&format!("invalid patch: {}", e)))?;
+    let hunk = match patch.hunks() {
+        [hunk] => hunk,
+        _ => return Err(retire("patch must contain exactly one hunk")),
+    };
+
+    let mut old_lines = vec![];
+    let mut deleted = None;
+    let mut inserted = None;
+    for line in hunk.lines() {
+        match line {
+            diffy::Line::Context(l) => old_lines.push(l.trim_end_matches('\n')),
+            diffy::Line::Delete(l) => {
+                deleted = Some(old_lines.len());
+                old_lines.push(l.trim_end_matches('\n'));
+            }
+            diffy::Line::Insert(l) => inserted = Some(l.trim_end_matches('\n')),
+        }
+    }
+
+    let (deleted, inserted) = match (deleted, inserted) {
+        (Some(deleted), Some(inserted)) => (deleted, inserted),
+        _ => return Err(retire("patch does not replace a line")),
+    };
+
+    let lines = content.split('\n').collect::<Vec<&str>>();
+    let expected_start = hunk.old_range().start().saturating_sub(1);
+
+    for f in 0..=fuzz {
+        let leading = f.min(deleted);
+        let trailing = f.min(old_lines.len() - deleted - 1);
+        let wanted = &old_lines[leading..old_lines.len() - trailing];
+
+        if let Some(start) = find_nearest(&lines, wanted, expected_start + leading) {
+            let line = start + deleted - leading;
+            let new_patch = create_patch(content, line, inserted);
+            let md5 = hex::encode(md5::compute(&new_patch).to_vec());
+
+            let mut rebased = mutation.clone();
+            rebased.patch_md5 = md5;
+            rebased.patch = new_patch;
+            rebased.line = line as i64;
+            rebased.base_commit = Some(base_commit.to_string());
+            if let Some(mutator) = &mutation.mutator {
+                rebased.fingerprint =
+                    Some(fingerprint(&mutation.file, &lines, line, inserted, mutator));
+            }
+
+            return Ok(rebased);
+        }
+    }
+
+    Err(retire(&format!(
+        "patch does not apply on {} with fuzz {}",
+        base_commit, fuzz
+    )))
+}
+
+/// Returns the position of `wanted` in `lines` closest to `expected`.
+fn find_nearest(lines: &[&str], wanted: &[&str], expected: usize) -> Option<usize> {
+    if wanted.len() > lines.len() {
+        return None;
+    }
+
+    let last = lines.len() - wanted.len();
+    let matches_at = |start: usize| lines[start..start + wanted.len()] == *wanted;
+
+    for offset in 0..=last.max(expected) {
+        if let Some(start) = expected.checked_sub(offset) {
+            if start <= last && matches_at(start) {
+                return Some(start);
+            }
+        }
+        let start = expected + offset;
+        if offset > 0 && start <= last && matches_at(start) {
+            return Some(start);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rebase_mutation;
+    use crate::mutate::{create_mutation, create_patch};
+
+    const ORIGINAL: &str = "int Foo(int a)\n{\n    int b = 2;\n    if (a == b) {\n        return 1;\n    }\n    return 0;\n}\n";
+
+    fn pending_mutation() -> common::Mutation {
+        let patch = create_patch(ORIGINAL, 3, "    if (a != b) {");
+        create_mutation("src/foo.cpp", &patch, 3, Some("master".to_string()), None)
+    }
+
+    #[test]
+    fn test_rebase_follows_shifted_line() {
+        let moved = format!("#include <foo.h>\n\n{}", ORIGINAL);
+        let rebased = rebase_mutation(&pending_mutation(), &moved, "abc", 0).unwrap();
+
+        assert_eq!(rebased.line, 5);
+        assert_eq!(rebased.base_commit, Some("abc".to_string()));
+        assert!(rebased.patch.contains("+    if (a != b) {"));
+    }
+
+    #[test]
+    fn test_rebase_with_fuzz() {
+        let changed = ORIGINAL.replace("int Foo(int a)", "int Foo(int a, int c)");
+
+        assert!(rebase_mutation(&pending_mutation(), &changed, "abc", 0).is_err());
+        assert_eq!(
+            rebase_mutation(&pending_mutation(), &changed, "abc", 2)
+                .unwrap()
+                .line,
+            3
+        );
+    }
+
+    #[test]
+    fn test_rebase_retires_removed_line() {
+        let removed = ORIGINAL.replace("    if (a == b) {", "    if (a < b) {");
+        let retired = rebase_mutation(&pending_mutation(), &removed, "abc", 3).unwrap_err();
+
+        assert!(retired.reason.contains("does not apply"));
+    }
+}
@@ -2,6 +2,7 @@ use fancy_regex::Regex;
 
 pub mod execution_flow;
 pub mod operator;
+pub mod python;
 pub mod std_algorithm;
 
 pub struct MutatorContext {
@@ -22,12 +23,22 @@ struct SimpleMutation {
 }
 
 fn simple_mutate(line: &str, patterns: &Vec<SimpleMutation>) -> Vec<String> {
+    mutate_outside_literals(line, patterns, &['"'])
+}
+
+/// Applies `patterns` to every match that is not inside a string literal
+/// delimited by one of `quotes`.
+fn mutate_outside_literals(
+    line: &str,
+    patterns: &Vec<SimpleMutation>,
+    quotes: &[char],
+) -> Vec<String> {
     let mut mutations = vec![];
 
     for simple_mutation in patterns {
         let matches = simple_mutation.from.find_iter(line);
 
-        let string_litterals = find_string_literals(line);
+        let string_litterals = find_string_literals(line, quotes);
 
         for m in matches.flatten() {
             if string_litterals
@@ -48,18 +59,18 @@ fn simple_mutate(line: &str, patterns: &Vec<SimpleMutation>) -> Vec<String> {
     mutations
 }
 
-fn find_string_literals(line: &str) -> Vec<(usize, usize)> {
+fn find_string_literals(line: &str, quotes: &[char]) -> Vec<(usize, usize)> {
     let mut result = vec![];
-    let mut in_string = false;
+    let mut in_string = None;
     let mut start = 0;
     for (i, c) in line.chars().enumerate() {
-        if in_string {
-            if c == '"' {
-                in_string = false;
+        if let Some(quote) = in_string {
+            if c == quote {
+                in_string = None;
                 result.push((start, i));
             }
-        } else if c == '"' {
-            in_string = true;
+        } else if quotes.contains(&c) {
+            in_string = Some(c);
             start = i;
         }
     }
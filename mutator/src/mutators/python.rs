@@ -0,0 +1,213 @@
+use fancy_regex::Regex;
+
+use super::{
+    find_string_literals, mutate_outside_literals, Mutator, MutatorContext, SimpleMutation,
+};
+
+const PYTHON_QUOTES: &[char] = &['"', '\''];
+
+pub struct PythonOperatorMutator {
+    patterns: Vec<SimpleMutation>,
+}
+
+impl Mutator for PythonOperatorMutator {
+    fn name(&self) -> &'static str {
+        "PythonOperatorMutator"
+    }
+
+    fn description(&self) -> &'static str {
+        "Mutates Python comparison operators such as == to !=, != to ==."
+    }
+
+    fn mutate(&self, ctx: &MutatorContext) -> Vec<String> {
+        mutate_outside_literals(&ctx.line_content, &self.patterns, PYTHON_QUOTES)
+    }
+}
+
+impl Default for PythonOperatorMutator {
+    fn default() -> Self {
+        Self {
+            patterns: vec![
+                SimpleMutation {
+                    from: Regex::new(r"==").unwrap(),
+                    to: vec!["!="],
+                },
+                SimpleMutation {
+                    from: Regex::new(r"!=").unwrap(),
+                    to: vec!["=="],
+                },
+            ],
+        }
+    }
+}
+
+pub struct PythonBoolOperatorMutator {
+    patterns: Vec<SimpleMutation>,
+}
+
+impl Mutator for PythonBoolOperatorMutator {
+    fn name(&self) -> &'static str {
+        "PythonBoolOperatorMutator"
+    }
+
+    fn description(&self) -> &'static str {
+        "Mutates Python boolean operators such as and to or, or to and, and removes not."
+    }
+
+    fn mutate(&self, ctx: &MutatorContext) -> Vec<String> {
+        mutate_outside_literals(&ctx.line_content, &self.patterns, PYTHON_QUOTES)
+    }
+}
+
+impl Default for PythonBoolOperatorMutator {
+    fn default() -> Self {
+        Self {
+            patterns: vec![
+                SimpleMutation {
+                    from: Regex::new(r" and ").unwrap(),
+                    to: vec![" or "],
+                },
+                SimpleMutation {
+                    from: Regex::new(r" or ").unwrap(),
+                    to: vec![" and "],
+                },
+                SimpleMutation {
+                    from: Regex::new(r"\bnot ").unwrap(),
+                    to: vec![""],
+                },
+            ],
+        }
+    }
+}
+
+pub struct PythonIndexMutator {
+    pattern: Regex,
+}
+
+impl Mutator for PythonIndexMutator {
+    fn name(&self) -> &'static str {
+        "PythonIndexMutator"
+    }
+
+    fn description(&self) -> &'static str {
+        "Shifts subscripts by one such as v[i] to v[i + 1] and v[i - 1]."
+    }
+
+    fn mutate(&self, ctx: &MutatorContext) -> Vec<String> {
+        let line = &ctx.line_content;
+        let string_litterals = find_string_literals(line, PYTHON_QUOTES);
+
+        let mut mutations = vec![];
+        for captures in self.pattern.captures_iter(line).flatten() {
+            let index = captures.get(1).unwrap();
+            if string_litterals
+                .iter()
+                .any(|(start, end)| index.start() >= *start && index.end() <= *end)
+            {
+                continue;
+            }
+
+            for op in ["+", "-"] {
+                let mut mutated_line = line.to_string();
+                mutated_line.replace_range(
+                    index.start()..index.end(),
+                    &format!("{} {} 1", index.as_str(), op),
+                );
+                mutations.push(mutated_line);
+            }
+        }
+
+        mutations
+    }
+}
+
+impl Default for PythonIndexMutator {
+    fn default() -> Self {
+        Self {
+            // Plain subscripts only: slices and dictionary keys are left alone.
+            pattern: Regex::new(r"(?<=[\w\)\]])\[(\w+)\]").unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mutators::MutatorContext;
+
+    struct TestCase {
+        line: &'static str,
+        expected: Vec<&'static str>,
+    }
+
+    fn run(mutator: &dyn Mutator, tests: Vec<TestCase>) {
+        for test in tests {
+            let ctx = MutatorContext {
+                line_content: test.line.to_string(),
+                file: "".to_string(),
+                line: 0,
+            };
+            let actual = mutator.mutate(&ctx);
+            assert_eq!(actual, test.expected);
+        }
+    }
+
+    #[test]
+    fn test_python_operator_mutator() {
+        run(
+            &PythonOperatorMutator::default(),
+            vec![
+                TestCase {
+                    line: "if self.nVersion == 1:",
+                    expected: vec!["if self.nVersion != 1:"],
+                },
+                TestCase {
+                    line: "assert x != 'a == b'",
+                    expected: vec!["assert x == 'a == b'"],
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn test_python_bool_operator_mutator() {
+        run(
+            &PythonBoolOperatorMutator::default(),
+            vec![
+                TestCase {
+                    line: "if a and b or c:",
+                    expected: vec!["if a or b or c:", "if a and b and c:"],
+                },
+                TestCase {
+                    line: "if not self.is_null():",
+                    expected: vec!["if self.is_null():"],
+                },
+                TestCase {
+                    line: "return self.annotation",
+                    expected: vec![],
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn test_python_index_mutator() {
+        run(
+            &PythonIndexMutator::default(),
+            vec![
+                TestCase {
+                    line: "txin = tx.vin[i]",
+                    expected: vec!["txin = tx.vin[i + 1]", "txin = tx.vin[i - 1]"],
+                },
+                TestCase {
+                    line: "op = script[0:1]",
+                    expected: vec![],
+                },
+                TestCase {
+                    line: "x = ['a']",
+                    expected: vec![],
+                },
+            ],
+        );
+    }
+}
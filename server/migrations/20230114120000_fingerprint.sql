@@ -0,0 +1,4 @@
+ALTER TABLE "mutations" ADD COLUMN "fingerprint" VARCHAR(255);
+ALTER TABLE "mutations" ADD COLUMN "mutator" VARCHAR(255);
+
+CREATE INDEX "mutations_fingerprint" ON "mutations" ("fingerprint");
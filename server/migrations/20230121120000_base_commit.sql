@@ -0,0 +1 @@
+ALTER TABLE "mutations" ADD COLUMN "base_commit" VARCHAR(40);
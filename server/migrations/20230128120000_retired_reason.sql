@@ -0,0 +1 @@
+ALTER TABLE "mutations" ADD COLUMN "retired_reason" TEXT;
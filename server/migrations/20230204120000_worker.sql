@@ -0,0 +1 @@
+ALTER TABLE "mutations" ADD COLUMN "worker" VARCHAR(255);
@@ -0,0 +1,2 @@
+ALTER TABLE "mutations" ADD COLUMN "lease_expires_at" INTEGER;
+ALTER TABLE "mutations" ADD COLUMN "attempts" INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS "workers" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    "owner" VARCHAR(255) NOT NULL,
+    "hostname" VARCHAR(255) NOT NULL,
+    "version" VARCHAR(255) NOT NULL,
+    "capabilities" TEXT NOT NULL DEFAULT '[]',
+    "current_mutation" INTEGER,
+    "registered_at" INTEGER NOT NULL,
+    "last_heartbeat" INTEGER NOT NULL,
+    "killed" INTEGER NOT NULL DEFAULT 0,
+    "not_killed" INTEGER NOT NULL DEFAULT 0,
+    "timeouts" INTEGER NOT NULL DEFAULT 0,
+    "errors" INTEGER NOT NULL DEFAULT 0,
+    "expired_leases" INTEGER NOT NULL DEFAULT 0,
+    UNIQUE ("owner", "hostname")
+);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS "tokens" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    "owner" VARCHAR(255) NOT NULL,
+    "token_hash" VARCHAR(64) NOT NULL UNIQUE,
+    "roles" VARCHAR(255) NOT NULL,
+    "created_at" INTEGER NOT NULL,
+    "expires_at" INTEGER,
+    "revoked_at" INTEGER
+);
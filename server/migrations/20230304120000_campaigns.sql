@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS "campaigns" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    "name" VARCHAR(255) NOT NULL UNIQUE,
+    "base_commit" VARCHAR(255),
+    "source" VARCHAR(255) NOT NULL,
+    "source_ref" TEXT,
+    "creator" VARCHAR(255) NOT NULL,
+    "created_at" INTEGER NOT NULL,
+    "state" VARCHAR(255) NOT NULL
+);
+
+-- Mutations uploaded before campaigns existed, or without one.
+INSERT INTO "campaigns" ("name", "source", "creator", "created_at", "state")
+VALUES ('default', 'files', 'unknown', CAST(strftime('%s', 'now') AS INTEGER), 'Open');
+
+ALTER TABLE "mutations" ADD COLUMN "campaign_id" INTEGER REFERENCES "campaigns" ("id");
+UPDATE "mutations" SET "campaign_id" = (SELECT "id" FROM "campaigns" WHERE "name" = 'default');
+CREATE INDEX "mutations_campaign_status" ON "mutations" ("campaign_id", "status");
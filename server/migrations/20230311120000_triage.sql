@@ -0,0 +1,18 @@
+-- Reviews of mutation results. The status a mutation had before each review
+-- is kept so the original result is never lost.
+CREATE TABLE IF NOT EXISTS "triage" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    "mutation_id" INTEGER NOT NULL REFERENCES "mutations" ("id") ON DELETE CASCADE,
+    "fingerprint" VARCHAR(255),
+    "previous_status" VARCHAR(255) NOT NULL,
+    "status" VARCHAR(255) NOT NULL,
+    "reason" TEXT NOT NULL,
+    "reviewer" VARCHAR(255) NOT NULL,
+    "created_at" INTEGER NOT NULL,
+    -- Set when the decision was copied from an earlier review of the same
+    -- mutant instead of being made by hand.
+    "carried_from" INTEGER REFERENCES "triage" ("id")
+);
+
+CREATE INDEX "triage_mutation" ON "triage" ("mutation_id");
+CREATE INDEX "triage_fingerprint" ON "triage" ("fingerprint");
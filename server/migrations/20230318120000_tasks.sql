@@ -0,0 +1,15 @@
+-- Survivors are followed up as test-writing tasks.
+ALTER TABLE "mutations" ADD COLUMN "task_state" VARCHAR(255);
+ALTER TABLE "mutations" ADD COLUMN "assignee" VARCHAR(255);
+UPDATE "mutations" SET "task_state" = 'open' WHERE "status" = 'NotKilled';
+CREATE INDEX "mutations_task_state" ON "mutations" ("task_state", "assignee");
+
+CREATE TABLE IF NOT EXISTS "comments" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    "mutation_id" INTEGER NOT NULL REFERENCES "mutations" ("id") ON DELETE CASCADE,
+    "author" VARCHAR(255) NOT NULL,
+    "body" TEXT NOT NULL,
+    "created_at" INTEGER NOT NULL
+);
+
+CREATE INDEX "comments_mutation" ON "comments" ("mutation_id");
@@ -0,0 +1,15 @@
+-- Results a mutation had before it was requeued.
+CREATE TABLE IF NOT EXISTS "results" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    "mutation_id" INTEGER NOT NULL REFERENCES "mutations" ("id") ON DELETE CASCADE,
+    "status" VARCHAR(255) NOT NULL,
+    "worker" VARCHAR(255),
+    "start_time" INTEGER,
+    "end_time" INTEGER,
+    "stdout" TEXT,
+    "stderr" TEXT,
+    "requeued_at" INTEGER NOT NULL,
+    "requeued_by" VARCHAR(255) NOT NULL
+);
+
+CREATE INDEX "results_mutation" ON "results" ("mutation_id");
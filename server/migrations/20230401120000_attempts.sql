@@ -0,0 +1,32 @@
+-- Every execution attempt gets its own row and the mutation takes the status
+-- of the latest one. Requeued attempts are marked rather than copied.
+CREATE TABLE "results_new" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    "mutation_id" INTEGER NOT NULL REFERENCES "mutations" ("id") ON DELETE CASCADE,
+    "status" VARCHAR(255) NOT NULL,
+    "worker" VARCHAR(255),
+    "worker_id" INTEGER REFERENCES "workers" ("id"),
+    "start_time" INTEGER,
+    "end_time" INTEGER,
+    "exit_code" INTEGER,
+    "stdout" TEXT,
+    "stderr" TEXT,
+    "requeued_at" INTEGER,
+    "requeued_by" VARCHAR(255)
+);
+
+INSERT INTO "results_new" ("id", "mutation_id", "status", "worker", "start_time", "end_time", "stdout", "stderr", "requeued_at", "requeued_by")
+SELECT "id", "mutation_id", "status", "worker", "start_time", "end_time", "stdout", "stderr", "requeued_at", "requeued_by" FROM "results";
+
+DROP TABLE "results";
+ALTER TABLE "results_new" RENAME TO "results";
+CREATE INDEX "results_mutation" ON "results" ("mutation_id");
+
+-- Results reported so far only live on the mutation. Those of triaged
+-- mutations stay there, their status no longer being the one that was run.
+INSERT INTO "results" ("mutation_id", "status", "worker", "start_time", "end_time", "stdout", "stderr")
+SELECT "id", "status", "worker", "start_time", "end_time", "stdout", "stderr" FROM "mutations"
+WHERE "status" IN ('Killed', 'NotKilled', 'Timeout', 'Error', 'Stale') AND "end_time" IS NOT NULL;
+
+UPDATE "mutations" SET "stdout" = NULL, "stderr" = NULL
+WHERE "id" IN (SELECT "mutation_id" FROM "results" WHERE "requeued_at" IS NULL);
@@ -0,0 +1,11 @@
+-- Logs of attempts, compressed with zstd and kept apart so listing attempts
+-- does not read them.
+CREATE TABLE IF NOT EXISTS "logs" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    "result_id" INTEGER NOT NULL REFERENCES "results" ("id") ON DELETE CASCADE,
+    "stream" VARCHAR(255) NOT NULL,
+    "data" BLOB NOT NULL,
+    "original_size" INTEGER NOT NULL,
+    "truncated" BOOLEAN NOT NULL,
+    UNIQUE ("result_id", "stream")
+);
@@ -0,0 +1,4 @@
+-- Work is handed out by priority, higher first.
+ALTER TABLE "campaigns" ADD COLUMN "priority" INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE "mutations" ADD COLUMN "priority" INTEGER NOT NULL DEFAULT 0;
+CREATE INDEX "mutations_status_campaign" ON "mutations" ("status", "campaign_id");
@@ -0,0 +1,4 @@
+-- Capabilities a worker needs to run a mutation, as a JSON array like the
+-- capabilities of workers. A campaign's apply to all of its mutations.
+ALTER TABLE "campaigns" ADD COLUMN "requires" TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE "mutations" ADD COLUMN "requires" TEXT NOT NULL DEFAULT '[]';
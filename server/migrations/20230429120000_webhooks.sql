@@ -0,0 +1,23 @@
+-- URLs notified of campaign events. `events` is a JSON array of event
+-- names and `files` a JSON array of watched file or directory prefixes. The
+-- secret signs payloads, so unlike tokens it is kept as is.
+CREATE TABLE IF NOT EXISTS "webhooks" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    "url" TEXT NOT NULL,
+    "secret" VARCHAR(255) NOT NULL,
+    "events" TEXT NOT NULL DEFAULT '[]',
+    "campaign_id" INTEGER REFERENCES "campaigns" ("id"),
+    "threshold" REAL,
+    "files" TEXT NOT NULL DEFAULT '[]',
+    "creator" VARCHAR(255) NOT NULL,
+    "created_at" INTEGER NOT NULL
+);
+
+-- Which side of a webhook's threshold the score of a campaign was last seen
+-- on, so each crossing is reported once.
+CREATE TABLE IF NOT EXISTS "webhook_scores" (
+    "webhook_id" INTEGER NOT NULL REFERENCES "webhooks" ("id") ON DELETE CASCADE,
+    "campaign_id" INTEGER NOT NULL REFERENCES "campaigns" ("id"),
+    "above" BOOLEAN NOT NULL,
+    PRIMARY KEY ("webhook_id", "campaign_id")
+);
@@ -0,0 +1,4 @@
+-- Registered worker holding the lease of a running mutation. Workers can
+-- share a token, so the owner of the token in "worker" does not tell them
+-- apart. Workers that did not register hold their leases with no id.
+ALTER TABLE "mutations" ADD COLUMN "worker_id" INTEGER REFERENCES "workers" ("id");
@@ -1,6 +1,78 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::Serialize;
 use thiserror::Error;
 
+/// Errors returned by the handlers. They are all sent as a JSON body such as
+/// `{"error": "not_found", "message": "Mutation 3 not found"}`.
 #[derive(Error, Debug)]
 pub enum ServerError {
-    
-}
\ No newline at end of file
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("Range not satisfiable, the content is {0} bytes long")]
+    RangeNotSatisfiable(usize),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Internal error: {0}")]
+    Internal(String),
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    error: &'static str,
+    message: String,
+}
+
+impl ServerError {
+    fn kind(&self) -> &'static str {
+        match self {
+            ServerError::NotFound(_) => "not_found",
+            ServerError::BadRequest(_) => "bad_request",
+            ServerError::Unauthorized(_) => "unauthorized",
+            ServerError::Forbidden(_) => "forbidden",
+            ServerError::Conflict(_) => "conflict",
+            ServerError::RangeNotSatisfiable(_) => "range_not_satisfiable",
+            ServerError::Database(_) => "database",
+            ServerError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl ResponseError for ServerError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ServerError::NotFound(_) => StatusCode::NOT_FOUND,
+            ServerError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ServerError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ServerError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ServerError::Conflict(_) => StatusCode::CONFLICT,
+            ServerError::RangeNotSatisfiable(_) => StatusCode::RANGE_NOT_SATISFIABLE,
+            ServerError::Database(_) | ServerError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if let ServerError::Database(_) | ServerError::Internal(_) = self {
+            println!("{}", self);
+        }
+
+        let mut response = HttpResponse::build(self.status_code());
+        if let ServerError::RangeNotSatisfiable(len) = self {
+            response.insert_header(("Content-Range", format!("bytes */{}", len)));
+        }
+
+        response.json(ErrorBody {
+            error: self.kind(),
+            message: self.to_string(),
+        })
+    }
+}
@@ -1,5 +1,6 @@
 extern crate dotenv;
 use clap::Parser;
+mod errors;
 mod server;
 
 use dotenv::dotenv;
@@ -17,19 +18,55 @@ struct Args {
     db: String,
     #[clap(
         long = "token",
-        help = "Authorized tokens (owner:token)",
-        required = true
+        help = "Tokens to add to the token store (owner:token[:role,...]) with roles submitter, worker, reviewer or admin"
     )]
     tokens: Vec<String>,
+    #[clap(
+        long,
+        help = "Seconds a worker holds a mutation without sending a heartbeat",
+        default_value = "600"
+    )]
+    lease_duration: i64,
+    #[clap(
+        long,
+        help = "Attempts before a mutation whose lease keeps expiring is marked as Error",
+        default_value = "3"
+    )]
+    max_attempts: i64,
+    #[clap(long, help = "Require a valid token on read endpoints")]
+    protect_reads: bool,
+    #[clap(
+        long,
+        help = "Bytes of each log kept, longer logs keep only their head and tail",
+        default_value = "4194304"
+    )]
+    max_log_size: usize,
+    #[clap(
+        long,
+        value_enum,
+        help = "How work is handed out to workers",
+        default_value = "priority"
+    )]
+    scheduling: server::Policy,
 }
 
 #[actix_web::main]
 async fn main() {
     dotenv().ok();
     let args = Args::parse();
-    if server::run(args.host, args.port, args.db, args.tokens)
-        .await
-        .is_err()
+    if server::run(server::Config {
+        host: args.host,
+        port: args.port,
+        db: args.db,
+        tokens: args.tokens,
+        lease_duration: args.lease_duration,
+        max_attempts: args.max_attempts,
+        protect_reads: args.protect_reads,
+        max_log_size: args.max_log_size,
+        scheduling: args.scheduling,
+    })
+    .await
+    .is_err()
     {
         panic!("Failed to start server");
     }
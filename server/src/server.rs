@@ -1,17 +1,88 @@
 use actix_cors::Cors;
 use actix_web::{
-    get, post,
+    delete, get, post,
     web::{self},
     App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
-use common::{Mutation, MutationResult, MutationStatus};
+use common::{Mutation, MutationResult, MutationStatus, RebaseResult};
 use serde::{Deserialize, Serialize};
-use sqlx::sqlite::{self, SqlitePool};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+use sqlx::QueryBuilder;
+use std::str::FromStr;
+use std::sync::Arc;
+
+mod campaigns;
+mod capabilities;
+mod listing;
+mod live;
+mod logs;
+mod migrations;
+mod requeue;
+mod results;
+mod scheduling;
+mod score;
+mod tasks;
+#[cfg(test)]
+mod testing;
+mod tokens;
+mod triage;
+mod webhooks;
+mod workers;
+
+pub use scheduling::Policy;
+use listing::{MutationFilter, Page};
+use crate::errors::ServerError;
+use tokens::{authorize, authorize_read, Role};
 
 async fn store_mutation(ctx: &Context, mutation: Mutation) {
+    if let Some(fingerprint) = &mutation.fingerprint {
+        let existing = sqlx::query!(
+            "SELECT id, status FROM mutations WHERE fingerprint = ? AND campaign_id = ?",
+            fingerprint,
+            mutation.campaign_id
+        )
+        .fetch_optional(&ctx.pool)
+        .await;
+
+        match existing {
+            Ok(Some(existing)) => {
+                // The mutant is already known. If it has not run yet, move it
+                // to where the mutated line now lives.
+                if existing.status == MutationStatus::Pending.to_string() {
+                    let r = sqlx::query(
+                        "UPDATE mutations SET patch_md5 = ?, line = ?, patch = ?, branch = ?, pr_number = ?, base_commit = ? WHERE id = ?",
+                    )
+                    .bind(mutation.patch_md5)
+                    .bind(mutation.line)
+                    .bind(mutation.patch)
+                    .bind(mutation.branch)
+                    .bind(mutation.pr_number)
+                    .bind(mutation.base_commit)
+                    .bind(existing.id)
+                    .execute(&ctx.pool)
+                    .await;
+
+                    match r {
+                        Ok(_) => println!("Mutation {} re-anchored", existing.id),
+                        Err(e) => println!("Error re-anchoring mutation: {}", e),
+                    }
+                } else {
+                    println!("Mutation already exists");
+                }
+                return;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                println!("Error looking up mutation: {}", e);
+                return;
+            }
+        }
+    }
+
     let exists = sqlx::query!(
-        "SELECT id FROM mutations WHERE patch_md5 = ?",
-        mutation.patch_md5
+        "SELECT id FROM mutations WHERE patch_md5 = ? AND campaign_id = ?",
+        mutation.patch_md5,
+        mutation.campaign_id
     )
     .fetch_one(&ctx.pool)
     .await
@@ -22,8 +93,9 @@ async fn store_mutation(ctx: &Context, mutation: Mutation) {
         return;
     }
 
+    let fingerprint = mutation.fingerprint.clone();
     let r = sqlx::query(
-        "INSERT INTO mutations (patch_md5, file, line, patch, branch, pr_number, status) VALUES (?, ?, ?, ?, ?, ?, ?)",
+        "INSERT INTO mutations (patch_md5, file, line, patch, branch, pr_number, status, fingerprint, mutator, base_commit, campaign_id, priority, requires) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
     ).bind(mutation.patch_md5)
         .bind(mutation.file)
         .bind(mutation.line)
@@ -31,12 +103,37 @@ async fn store_mutation(ctx: &Context, mutation: Mutation) {
         .bind(mutation.branch)
         .bind(mutation.pr_number)
         .bind(mutation.status)
+        .bind(mutation.fingerprint)
+        .bind(mutation.mutator)
+        .bind(mutation.base_commit)
+        .bind(mutation.campaign_id)
+        .bind(mutation.priority)
+        .bind(capabilities::to_json(&mutation.requires))
         .execute(&ctx.pool)
         .await;
 
-    match r {
-        Ok(_) => println!("Mutation stored"),
-        Err(e) => println!("Error storing mutation: {}", e),
+    let id = match r {
+        Ok(r) => r.last_insert_rowid(),
+        Err(e) => {
+            println!("Error storing mutation: {}", e);
+            return;
+        }
+    };
+    println!("Mutation stored");
+
+    // Mutants reviewed in an earlier campaign do not need reviewing again.
+    if let Some(fingerprint) = fingerprint {
+        match triage::carry_over(&ctx.pool, id, &fingerprint).await {
+            Ok(Some(status)) => {
+                println!(
+                    "Mutation {} triaged as {} from an earlier review",
+                    id, status
+                );
+                ctx.live.publish_status(id, &status, None);
+            }
+            Ok(None) => {}
+            Err(e) => println!("Error carrying over triage: {}", e),
+        }
     }
 }
 #[get("/")]
@@ -44,23 +141,33 @@ async fn index() -> impl Responder {
     HttpResponse::Ok().body("Hello world!")
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct Params {
-    file: Option<String>,
+fn parse_query<T: serde::de::DeserializeOwned>(req: &HttpRequest) -> Result<T, ServerError> {
+    web::Query::<T>::from_query(req.query_string())
+        .map(|q| q.into_inner())
+        .map_err(|e| ServerError::BadRequest(format!("Invalid parameters: {}", e)))
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, sqlx::FromRow)]
 struct MutationListItem {
     id: i64,
     patch_md5: String,
     file: String,
     line: i64,
-    patch: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    patch: Option<String>,
     branch: Option<String>,
     pr_number: Option<i64>,
     status: String,
     start_time: Option<i64>,
     end_time: Option<i64>,
+    fingerprint: Option<String>,
+    mutator: Option<String>,
+    base_commit: Option<String>,
+    retired_reason: Option<String>,
+    campaign_id: Option<i64>,
+    task_state: Option<String>,
+    assignee: Option<String>,
+    priority: i64,
 }
 
 #[get("/mutations/{status}")]
@@ -68,183 +175,399 @@ async fn list_mutations(
     path: web::Path<String>,
     req: HttpRequest,
     ctx: web::Data<Context>,
-) -> impl Responder {
+) -> Result<HttpResponse, ServerError> {
+    authorize_read(&req, &ctx).await?;
+
     let status = path.into_inner();
-    let params = web::Query::<Params>::from_query(req.query_string()).unwrap();
-    let file = params.file.as_ref();
+    let filter: MutationFilter = parse_query(&req)?;
+    let page: Page = parse_query(&req)?;
+    page.validate().map_err(ServerError::BadRequest)?;
 
-    if file.is_none() {
-        let mutations = sqlx::query_as!(MutationListItem, "SELECT id, patch_md5, file, line, patch, branch, pr_number, status, start_time, end_time FROM mutations WHERE status = ? ORDER BY end_time DESC", status)
-            .fetch_all(&ctx.pool)
-            .await
-            .unwrap();
+    let mut count = QueryBuilder::new("SELECT COUNT(*) FROM mutations WHERE status = ");
+    count.push_bind(&status);
+    filter.push_conditions(&mut count);
+    let (total,): (i64,) = count.build_query_as().fetch_one(&ctx.pool).await?;
 
-        HttpResponse::Ok().json(mutations)
-    } else {
-        let file = file.unwrap();
-        let mutations = sqlx::query_as!(MutationListItem, "SELECT id, patch_md5, file, line, patch, branch, pr_number, status, start_time, end_time FROM mutations WHERE status = ? AND file = ? ORDER BY end_time DESC", status, file)
-            .fetch_all(&ctx.pool)
-            .await
-            .unwrap();
+    let mut query = QueryBuilder::new(format!(
+        "SELECT id, patch_md5, file, line, {}, branch, pr_number, status, start_time, end_time, fingerprint, mutator, base_commit, retired_reason, campaign_id, task_state, assignee, priority FROM mutations WHERE status = ",
+        if page.patch { "patch" } else { "NULL AS patch" }
+    ));
+    query.push_bind(&status);
+    filter.push_conditions(&mut query);
+    page.push_clauses(&mut query);
+    let mutations: Vec<MutationListItem> = query.build_query_as().fetch_all(&ctx.pool).await?;
 
-        HttpResponse::Ok().json(mutations)
-    }
+    Ok(HttpResponse::Ok()
+        .insert_header(("X-Total-Count", total))
+        .json(mutations))
 }
 
 #[get("/mutations/{status}/files")]
 async fn list_mutations_files(
     path: web::Path<String>,
+    req: HttpRequest,
     ctx: web::Data<Context>,
-) -> impl Responder {
-    let status = path.into_inner();
+) -> Result<HttpResponse, ServerError> {
+    authorize_read(&req, &ctx).await?;
 
-    // Select file FROM mutations WHERE status = ? GROUP BY file
+    let status = path.into_inner();
+    let filter: MutationFilter = parse_query(&req)?;
 
-    let files = sqlx::query!(
-        "SELECT file FROM mutations WHERE status = ? GROUP BY file",
-        status
-    ).fetch_all(&ctx.pool)
-        .await
-        .unwrap();
+    let mut query = QueryBuilder::new("SELECT file FROM mutations WHERE status = ");
+    query.push_bind(&status);
+    filter.push_conditions(&mut query);
+    query.push(" GROUP BY file");
 
-    let files: Vec<String> = files.into_iter().map(|f| f.file).collect();
+    let files: Vec<(String,)> = query.build_query_as().fetch_all(&ctx.pool).await?;
+    let files: Vec<String> = files.into_iter().map(|(f,)| f).collect();
 
-    HttpResponse::Ok().json(files)
+    Ok(HttpResponse::Ok().json(files))
 }
 
 #[get("/mutations/{status}/count")]
 async fn count_mutations(
     path: web::Path<String>,
+    req: HttpRequest,
     ctx: web::Data<Context>,
-) -> impl Responder {
+) -> Result<HttpResponse, ServerError> {
+    authorize_read(&req, &ctx).await?;
+
     let status = path.into_inner();
+    let filter: MutationFilter = parse_query(&req)?;
 
-    let count = sqlx::query!(
-        "SELECT COUNT(*) as count FROM mutations WHERE status = ?",
-        status
-    ).fetch_one(&ctx.pool)
-        .await
-        .unwrap();
+    let mut query = QueryBuilder::new("SELECT COUNT(*) FROM mutations WHERE status = ");
+    query.push_bind(&status);
+    filter.push_conditions(&mut query);
+
+    let (count,): (i64,) = query.build_query_as().fetch_one(&ctx.pool).await?;
 
-    HttpResponse::Ok().json(count.count)
+    Ok(HttpResponse::Ok().json(count))
 }
 
 #[get("/mutation/{id}")]
-async fn get_mutation(ctx: web::Data<Context>, req: HttpRequest) -> impl Responder {
-    let id = req.match_info().get("id").unwrap_or("0");
+async fn get_mutation(
+    req: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ServerError> {
+    authorize_read(&req, &ctx).await?;
 
-    let mutation = sqlx::query_as!(Mutation, "SELECT * FROM mutations WHERE id = ?", id)
-        .fetch_one(&ctx.pool)
-        .await
-        .unwrap();
+    let id = id.into_inner();
+    let mut mutation = fetch_mutation(&ctx.pool, id)
+        .await?
+        .ok_or_else(|| mutation_not_found(id))?;
+    results::attach_logs(&ctx.pool, &mut mutation).await?;
+
+    Ok(HttpResponse::Ok().json(mutation))
+}
+
+fn mutation_not_found(id: i64) -> ServerError {
+    ServerError::NotFound(format!("Mutation {} not found", id))
+}
+
+/// A row of `mutations`, whose requirements are stored as JSON.
+struct MutationRow {
+    id: i64,
+    patch_md5: String,
+    file: String,
+    line: i64,
+    patch: String,
+    branch: Option<String>,
+    pr_number: Option<i64>,
+    status: String,
+    start_time: Option<i64>,
+    end_time: Option<i64>,
+    stderr: Option<String>,
+    stdout: Option<String>,
+    fingerprint: Option<String>,
+    mutator: Option<String>,
+    base_commit: Option<String>,
+    retired_reason: Option<String>,
+    worker: Option<String>,
+    worker_id: Option<i64>,
+    lease_expires_at: Option<i64>,
+    attempts: i64,
+    campaign_id: Option<i64>,
+    task_state: Option<String>,
+    assignee: Option<String>,
+    priority: i64,
+    requires: String,
+}
+
+impl From<MutationRow> for Mutation {
+    fn from(row: MutationRow) -> Self {
+        Mutation {
+            id: row.id,
+            patch_md5: row.patch_md5,
+            file: row.file,
+            line: row.line,
+            patch: row.patch,
+            branch: row.branch,
+            pr_number: row.pr_number,
+            status: row.status,
+            start_time: row.start_time,
+            end_time: row.end_time,
+            stderr: row.stderr,
+            stdout: row.stdout,
+            fingerprint: row.fingerprint,
+            mutator: row.mutator,
+            base_commit: row.base_commit,
+            retired_reason: row.retired_reason,
+            worker: row.worker,
+            worker_id: row.worker_id,
+            lease_expires_at: row.lease_expires_at,
+            attempts: row.attempts,
+            campaign_id: row.campaign_id,
+            task_state: row.task_state,
+            assignee: row.assignee,
+            priority: row.priority,
+            requires: capabilities::from_json(&row.requires),
+        }
+    }
+}
+
+async fn fetch_mutation(pool: &SqlitePool, id: i64) -> Result<Option<Mutation>, sqlx::Error> {
+    let row = sqlx::query_as!(MutationRow, "SELECT * FROM mutations WHERE id = ?", id)
+        .fetch_optional(pool)
+        .await?;
 
-    HttpResponse::Ok().json(mutation)
+    Ok(row.map(Mutation::from))
 }
 
+/// Largest batch of mutations handed out by a single `get_work`.
+const MAX_BATCH: i64 = 100;
+
+#[derive(Debug, Deserialize)]
+struct WorkParams {
+    /// Without it a single mutation is returned, as before batches existed.
+    batch: Option<i64>,
+}
+
+/// Hands out the next mutation, or with `?batch=N` up to N mutations that
+/// share its checkout so the worker only checks out once.
 #[post("/get_work")]
-async fn get_work(request: HttpRequest, ctx: web::Data<Context>) -> impl Responder {
-    // Get Authorization header
-    let auth_header = request.headers().get("Authorization");
-    if auth_header.is_none() {
-        return HttpResponse::Unauthorized().body("Missing Authorization header");
+async fn get_work(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+) -> Result<HttpResponse, ServerError> {
+    let owner = authorize(&request, &ctx, Role::Worker).await?;
+
+    let params: WorkParams = parse_query(&request)?;
+    let batch = params.batch.unwrap_or(1);
+    if !(1..=MAX_BATCH).contains(&batch) {
+        return Err(ServerError::BadRequest(format!(
+            "batch must be between 1 and {}",
+            MAX_BATCH
+        )));
+    }
+
+    // Pick and claim the mutations in a single statement, so two workers
+    // polling at the same time can never be handed the same one. The rows are
+    // fetched in full so the statement runs to completion before the claimed
+    // mutations are read back.
+    let pending = MutationStatus::Pending.to_string();
+    let running = MutationStatus::Running.to_string();
+    let now = chrono::Utc::now().timestamp();
+    let lease_expires_at = now + ctx.lease_duration;
+    let worker_id = workers::worker_id(&request);
+    let mut claim = QueryBuilder::new("UPDATE mutations SET status = ");
+    claim
+        .push_bind(&running)
+        .push(", start_time = ")
+        .push_bind(now)
+        .push(", worker = ")
+        .push_bind(&owner)
+        .push(", worker_id = ")
+        .push_bind(worker_id)
+        .push(", lease_expires_at = ")
+        .push_bind(lease_expires_at)
+        .push(
+            ", attempts = attempts + 1 WHERE id IN (SELECT b.id FROM mutations b JOIN mutations n ON n.id = (",
+        );
+    scheduling::push_next_mutation(
+        &mut claim,
+        ctx.scheduling,
+        &pending,
+        &running,
+        &owner,
+        worker_id,
+    );
+    // The rest of the batch is checked out the same way as the picked one.
+    claim
+        .push(") WHERE b.status = ")
+        .push_bind(&pending)
+        .push(
+            " AND (b.id = n.id OR (b.base_commit = n.base_commit AND b.pr_number IS n.pr_number AND b.branch IS n.branch))",
+        );
+    capabilities::push_requirements_met(&mut claim, "b", &owner, worker_id);
+    claim
+        .push(" ORDER BY b.id = n.id DESC, b.file, b.line, b.id LIMIT ")
+        .push_bind(batch)
+        .push(") AND status = ")
+        .push_bind(&pending)
+        .push(" RETURNING id");
+    let claimed: Vec<(i64,)> = claim.build_query_as().fetch_all(&ctx.pool).await?;
+
+    let mut mutations = vec![];
+    for (id,) in claimed {
+        if let Some(mutation) = fetch_mutation(&ctx.pool, id).await? {
+            mutations.push(mutation);
+        }
     }
+    mutations.sort_by(|a, b| (&a.file, a.line, a.id).cmp(&(&b.file, b.line, b.id)));
 
-    // Get the token from the Authorization header
-    let auth_header = auth_header.unwrap().to_str().unwrap();
-    let owner = is_authorized(auth_header.to_string(), ctx.tokens.clone());
-    if owner.is_none() {
-        return HttpResponse::Unauthorized().body("Invalid token");
+    // Polling counts as a heartbeat, so idle workers are not shown offline.
+    if let Some(worker_id) = worker_id {
+        let first = mutations.first().map(|mutation| mutation.id);
+        workers::record_heartbeat(&ctx.pool, worker_id, &owner, first).await;
+    }
+    if mutations.is_empty() {
+        return Ok(HttpResponse::NoContent().body("No work available"));
+    }
+
+    for mutation in &mutations {
+        ctx.live
+            .publish_status(mutation.id, &mutation.status, Some(&owner));
     }
 
-    let status = MutationStatus::Pending.to_string();
-    let mutation = sqlx::query_as!(
-        Mutation,
-        "SELECT * FROM mutations WHERE status = ? LIMIT 1",
-        status
+    if params.batch.is_some() {
+        return Ok(HttpResponse::Ok().json(mutations));
+    }
+    Ok(HttpResponse::Ok().json(&mutations[0]))
+}
+
+#[post("/mutations/{id}/heartbeat")]
+async fn heartbeat(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ServerError> {
+    let owner = authorize(&request, &ctx, Role::Worker).await?;
+
+    let id = id.into_inner();
+    let lease_expires_at = chrono::Utc::now().timestamp() + ctx.lease_duration;
+    let r = sqlx::query(
+        "UPDATE mutations SET lease_expires_at = ? WHERE id = ? AND status = ? AND worker = ? AND worker_id IS ?",
     )
-    .fetch_one(&ctx.pool)
+    .bind(lease_expires_at)
+    .bind(id)
+    .bind(MutationStatus::Running.to_string())
+    .bind(&owner)
+    .bind(workers::worker_id(&request))
+    .execute(&ctx.pool)
     .await;
 
-    match mutation {
-        Ok(mutation) => {
-            let r = sqlx::query("UPDATE mutations SET status = ?, start_time = ? WHERE id = ?")
-                .bind(MutationStatus::Running.to_string())
-                .bind(chrono::Utc::now().timestamp())
-                .bind(mutation.id)
-                .execute(&ctx.pool)
-                .await;
+    if let Some(worker_id) = workers::worker_id(&request) {
+        workers::record_heartbeat(&ctx.pool, worker_id, &owner, None).await;
+    }
 
-            match r {
-                Ok(_) => HttpResponse::Ok().json(mutation),
-                Err(e) => HttpResponse::InternalServerError()
-                    .body(format!("Error updating mutation: {}", e)),
-            }
-        }
-        Err(e) => HttpResponse::NoContent().body(format!("No work available: {}", e)),
+    if r?.rows_affected() == 0 {
+        return Err(ServerError::Conflict(format!(
+            "Mutation {} is not leased to you",
+            id
+        )));
     }
+
+    Ok(HttpResponse::Ok().json(lease_expires_at))
+}
+
+/// Returns the mutations whose lease expired to the queue, or marks them as
+/// `Error` once they have been attempted `max_attempts` times. Mutations
+/// claimed before leases existed expire `lease_duration` after they started.
+async fn requeue_expired_leases(
+    pool: &SqlitePool,
+    lease_duration: i64,
+    max_attempts: i64,
+) -> Result<Vec<(i64, String)>, sqlx::Error> {
+    let now = chrono::Utc::now().timestamp();
+
+    sqlx::query(
+        "UPDATE workers SET expired_leases = expired_leases + 1, current_mutation = NULL
+        WHERE current_mutation IN (SELECT id FROM mutations WHERE status = ? AND COALESCE(lease_expires_at, start_time + ?) < ?)",
+    )
+    .bind(MutationStatus::Running.to_string())
+    .bind(lease_duration)
+    .bind(now)
+    .execute(pool)
+    .await?;
+
+    sqlx::query_as(
+        "UPDATE mutations SET status = CASE WHEN attempts >= ? THEN ? ELSE ? END, worker = NULL, worker_id = NULL, lease_expires_at = NULL
+        WHERE status = ? AND COALESCE(lease_expires_at, start_time + ?) < ?
+        RETURNING id, status",
+    )
+    .bind(max_attempts)
+    .bind(MutationStatus::Error.to_string())
+    .bind(MutationStatus::Pending.to_string())
+    .bind(MutationStatus::Running.to_string())
+    .bind(lease_duration)
+    .bind(now)
+    .fetch_all(pool)
+    .await
 }
 
 #[post("/mutations/{id}")]
 async fn submit_mutation_result(
     request: HttpRequest,
     ctx: web::Data<Context>,
-    id: web::Path<String>,
+    id: web::Path<i64>,
     result: web::Json<MutationResult>,
-) -> impl Responder {
-    let auth_header = request.headers().get("Authorization");
-    if auth_header.is_none() {
-        return HttpResponse::Unauthorized().body("Missing Authorization header");
-    }
-
-    // Get the token from the Authorization header
-    let auth_header = auth_header.unwrap().to_str().unwrap();
-    let owner = is_authorized(auth_header.to_string(), ctx.tokens.clone());
-    if owner.is_none() {
-        return HttpResponse::Unauthorized().body("Invalid token");
-    }
+) -> Result<HttpResponse, ServerError> {
+    let owner = authorize(&request, &ctx, Role::Worker).await?;
 
-    let key = id.into_inner();
+    let id = id.into_inner();
     println!(
         "Received result for mutation {} from {}: {:?}",
-        key,
-        owner.unwrap(),
-        result.status
+        id, owner, result.status
     );
 
-    let mutation = sqlx::query_as!(Mutation, "SELECT * FROM mutations WHERE id = ?", key)
-        .fetch_one(&ctx.pool)
-        .await;
+    let mutation = fetch_mutation(&ctx.pool, id)
+        .await?
+        .ok_or_else(|| mutation_not_found(id))?;
 
-    match mutation {
-        Ok(mut mutation) => {
-            if result.stderr.is_some() {
-                mutation.stderr = result.clone().stderr;
-            }
+    // Workers sharing a token are told apart by their registration.
+    if mutation.status != MutationStatus::Running.to_string()
+        || mutation.worker.as_ref() != Some(&owner)
+        || mutation.worker_id != workers::worker_id(&request)
+    {
+        return Err(ServerError::Conflict(format!(
+            "Mutation {} is not leased to you",
+            id
+        )));
+    }
 
-            if result.stdout.is_some() {
-                mutation.stdout = result.clone().stdout;
-            }
+    results::record_attempt(
+        &ctx.pool,
+        &mutation,
+        &result,
+        &owner,
+        workers::worker_id(&request),
+        ctx.max_log_size,
+    )
+    .await?;
 
-            let r = sqlx::query(
-                "UPDATE mutations SET status = ?, end_time = ?, stderr = ?, stdout = ? WHERE id = ?",
-            ).bind(result.status.to_string())
-                .bind(chrono::Utc::now().timestamp())
-                .bind(mutation.stderr)
-                .bind(mutation.stdout)
-                .bind(mutation.id)
-                .execute(&ctx.pool)
-                .await;
+    campaigns::refresh_states(&ctx.pool).await;
+    if let Some(worker_id) = workers::worker_id(&request) {
+        workers::record_result(&ctx.pool, worker_id, &owner, &result.status).await;
+    }
+    ctx.live
+        .publish_status(id, &result.status.to_string(), Some(&owner));
 
-            match r {
-                Ok(_) => HttpResponse::Ok().body("Mutation result stored"),
-                Err(e) => HttpResponse::InternalServerError()
-                    .body(format!("Error updating mutation: {}", e)),
-            }
-        }
-        Err(e) => {
-            HttpResponse::InternalServerError().body(format!("Error fetching mutation: {}", e))
-        }
+    tasks::record_result(
+        &ctx.pool,
+        id,
+        mutation.fingerprint.as_deref(),
+        &result.status,
+    )
+    .await;
+    if let Some(campaign_id) = mutation.campaign_id {
+        webhooks::check_score(&ctx.pool, campaign_id).await;
     }
+    if result.status == MutationStatus::NotKilled {
+        webhooks::mutation_survived(&ctx.pool, id).await;
+    }
+
+    Ok(HttpResponse::Ok().body("Mutation result stored"))
 }
 
 #[post("/mutations")]
@@ -252,69 +575,297 @@ async fn add_mutations(
     request: HttpRequest,
     ctx: web::Data<Context>,
     mutations: web::Json<Vec<Mutation>>,
-) -> impl Responder {
-    let auth_header = request.headers().get("Authorization");
-    if auth_header.is_none() {
-        return HttpResponse::Unauthorized().body("Missing Authorization header");
+) -> Result<HttpResponse, ServerError> {
+    authorize(&request, &ctx, Role::Submitter).await?;
+
+    let default_campaign = campaigns::default_campaign_id(&ctx.pool).await?;
+
+    let mut mutations = mutations.into_inner();
+    let mut campaign_ids = vec![];
+    for mutation in mutations.iter_mut() {
+        let campaign_id = *mutation.campaign_id.get_or_insert(default_campaign);
+        if !campaign_ids.contains(&campaign_id) {
+            campaign_ids.push(campaign_id);
+        }
     }
 
-    // Get the token from the Authorization header
-    let auth_header = auth_header.unwrap().to_str().unwrap();
-    if is_authorized(auth_header.to_string(), ctx.tokens.clone()).is_none() {
-        return HttpResponse::Unauthorized().body("Invalid token");
+    for campaign_id in campaign_ids {
+        if !campaigns::campaign_exists(&ctx.pool, campaign_id).await? {
+            return Err(ServerError::BadRequest(format!(
+                "Campaign {} does not exist",
+                campaign_id
+            )));
+        }
     }
 
-    for mutation in mutations.into_inner() {
+    for mutation in mutations {
         println!("Received mutation: {:?}:{:?}", mutation.file, mutation.line);
         store_mutation(&ctx, mutation).await;
     }
 
-    HttpResponse::Ok().finish()
+    campaigns::refresh_states(&ctx.pool).await;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("/rebase")]
+async fn rebase_mutations(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    rebase: web::Json<RebaseResult>,
+) -> Result<HttpResponse, ServerError> {
+    authorize(&request, &ctx, Role::Submitter).await?;
+
+    let rebase = rebase.into_inner();
+    let pending = MutationStatus::Pending.to_string();
+    println!(
+        "Rebasing onto {}: {} rebased, {} retired",
+        rebase.base_commit,
+        rebase.rebased.len(),
+        rebase.retired.len()
+    );
+
+    // Only mutations still waiting in the queue are touched, in case a worker
+    // picked one up in the meantime.
+    for mutation in rebase.rebased {
+        sqlx::query(
+            "UPDATE mutations SET patch_md5 = ?, line = ?, patch = ?, fingerprint = ?, base_commit = ? WHERE id = ? AND status = ?",
+        )
+        .bind(mutation.patch_md5)
+        .bind(mutation.line)
+        .bind(mutation.patch)
+        .bind(mutation.fingerprint)
+        .bind(&rebase.base_commit)
+        .bind(mutation.id)
+        .bind(&pending)
+        .execute(&ctx.pool)
+        .await?;
+    }
+
+    let retired_status = MutationStatus::Retired.to_string();
+    for retired in rebase.retired {
+        let r = sqlx::query(
+            "UPDATE mutations SET status = ?, retired_reason = ? WHERE id = ? AND status = ?",
+        )
+        .bind(&retired_status)
+        .bind(retired.reason)
+        .bind(retired.mutation_id)
+        .bind(&pending)
+        .execute(&ctx.pool)
+        .await?;
+        if r.rows_affected() > 0 {
+            ctx.live
+                .publish_status(retired.mutation_id, &retired_status, None);
+        }
+    }
+
+    campaigns::refresh_states(&ctx.pool).await;
+
+    Ok(HttpResponse::Ok().finish())
 }
 
-#[derive(Clone, Debug)]
-struct Token {
-    owner: String,
-    token: String,
+#[post("/mutation/{id}/ignore")]
+async fn ignore_mutation(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ServerError> {
+    authorize(&request, &ctx, Role::Admin).await?;
+
+    let id = id.into_inner();
+    let ignored = MutationStatus::Ignored.to_string();
+    let r = sqlx::query("UPDATE mutations SET status = ? WHERE id = ?")
+        .bind(&ignored)
+        .bind(id)
+        .execute(&ctx.pool)
+        .await?;
+    campaigns::refresh_states(&ctx.pool).await;
+
+    if r.rows_affected() == 0 {
+        return Err(mutation_not_found(id));
+    }
+    ctx.live.publish_status(id, &ignored, None);
+
+    Ok(HttpResponse::Ok().body("Mutation ignored"))
+}
+
+#[delete("/mutation/{id}")]
+async fn delete_mutation(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ServerError> {
+    authorize(&request, &ctx, Role::Admin).await?;
+
+    let id = id.into_inner();
+    let r = sqlx::query("DELETE FROM mutations WHERE id = ?")
+        .bind(id)
+        .execute(&ctx.pool)
+        .await?;
+    campaigns::refresh_states(&ctx.pool).await;
+
+    if r.rows_affected() == 0 {
+        return Err(mutation_not_found(id));
+    }
+
+    Ok(HttpResponse::Ok().body("Mutation deleted"))
 }
 
 struct Context {
     pool: SqlitePool,
-    tokens: Vec<Token>,
+    lease_duration: i64,
+    protect_reads: bool,
+    max_log_size: usize,
+    scheduling: Policy,
+    live: Arc<live::Live>,
 }
 
-fn is_authorized(token: String, tokens: Vec<Token>) -> Option<String> {
-    for t in tokens {
-        if t.token == token {
-            return Some(t.owner);
-        }
-    }
+fn routes(cfg: &mut web::ServiceConfig) {
+    // Malformed bodies and ids get the same JSON errors as the handlers.
+    cfg.app_data(
+        web::JsonConfig::default()
+            .limit(1024 * 1024 * 50)
+            .error_handler(|e, _| ServerError::BadRequest(format!("Invalid body: {}", e)).into()),
+    )
+    .app_data(web::PathConfig::default().error_handler(|e, _| {
+        ServerError::BadRequest(format!("Invalid path: {}", e)).into()
+    }));
 
-    None
+    cfg.service(results::flaky_mutations)
+        .service(list_mutations)
+        .service(get_work)
+        .service(index)
+        .service(add_mutations)
+        .service(heartbeat)
+        .service(requeue::requeue_mutations)
+        .service(workers::register_worker)
+        .service(workers::list_workers)
+        .service(submit_mutation_result)
+        .service(get_mutation)
+        .service(list_mutations_files)
+        .service(count_mutations)
+        .service(rebase_mutations)
+        .service(requeue::requeue_mutation)
+        .service(results::list_attempts)
+        .service(logs::get_log)
+        .service(live::publish_log)
+        .service(live::follow_log)
+        .service(live::events)
+        .service(ignore_mutation)
+        .service(delete_mutation)
+        .service(tokens::create_token)
+        .service(tokens::list_tokens)
+        .service(tokens::revoke_token)
+        .service(tokens::expire_token)
+        .service(campaigns::create_campaign)
+        .service(campaigns::list_campaigns)
+        .service(campaigns::get_campaign)
+        .service(scheduling::set_campaign_priority)
+        .service(scheduling::set_mutation_priority)
+        .service(capabilities::set_campaign_requirements)
+        .service(capabilities::set_mutation_requirements)
+        .service(score::get_score)
+        .service(triage::triage_mutation)
+        .service(triage::triage_history)
+        .service(tasks::update_task)
+        .service(tasks::list_comments)
+        .service(tasks::add_comment)
+        .service(webhooks::create_webhook)
+        .service(webhooks::list_webhooks)
+        .service(webhooks::delete_webhook);
 }
 
-pub async fn run(host: String, port: u16, db: String, tokens: Vec<String>) -> std::io::Result<()> {
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub db: String,
+    pub tokens: Vec<String>,
+    pub lease_duration: i64,
+    pub max_attempts: i64,
+    pub protect_reads: bool,
+    pub max_log_size: usize,
+    pub scheduling: Policy,
+}
+
+pub async fn run(config: Config) -> std::io::Result<()> {
+    let Config {
+        host,
+        port,
+        db,
+        tokens,
+        lease_duration,
+        max_attempts,
+        protect_reads,
+        max_log_size,
+        scheduling,
+    } = config;
     println!("Starting server on {}:{}", host, port);
 
-    // Parse tokens : Owner:Token
+    // Parse tokens : Owner:Token[:role,role]
     let mut parsed_tokens = Vec::new();
     for token in tokens {
         let parts: Vec<&str> = token.split(':').collect();
-        if parts.len() != 2 {
+        if parts.len() != 2 && parts.len() != 3 {
             panic!("Invalid token: {}", token);
         }
-        parsed_tokens.push(Token {
-            owner: parts[0].to_string(),
-            token: parts[1].to_string(),
-        });
 
-        println!("Added token for {}", parts[0]);
+        // Tokens without roles keep what they could do before roles existed.
+        let roles = match parts.get(2) {
+            Some(roles) => roles
+                .split(',')
+                .map(|r| r.parse::<Role>())
+                .collect::<Result<Vec<Role>, String>>()
+                .unwrap_or_else(|e| panic!("Invalid token {}: {}", token, e)),
+            None => vec![Role::Submitter, Role::Worker],
+        };
+
+        parsed_tokens.push((parts[0].to_string(), parts[1].to_string(), roles));
     }
 
-    let pool = sqlite::SqlitePool::connect(&db)
+    let options = SqliteConnectOptions::from_str(&db)
+        .expect("Invalid database URL")
+        .create_if_missing(true);
+    let pool = SqlitePool::connect_with(options)
         .await
         .expect("Failed to connect to database");
 
+    if let Err(e) = migrations::migrate(&pool).await {
+        panic!("{}", e);
+    }
+
+    for (owner, token, roles) in parsed_tokens {
+        tokens::seed_token(&pool, &owner, &token, &roles)
+            .await
+            .expect("Failed to store token");
+        println!("Added token for {} ({:?})", owner, roles);
+    }
+
+    let live = Arc::new(live::Live::new());
+    let requeue_pool = pool.clone();
+    let requeue_live = live.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(
+            lease_duration.clamp(1, 60) as u64,
+        ));
+        loop {
+            interval.tick().await;
+            match requeue_expired_leases(&requeue_pool, lease_duration, max_attempts).await {
+                Ok(expired) if expired.is_empty() => {}
+                Ok(expired) => {
+                    println!(
+                        "Requeued {} mutations with an expired lease",
+                        expired.len()
+                    );
+                    for (id, status) in expired {
+                        requeue_live.publish_status(id, &status, None);
+                    }
+                    campaigns::refresh_states(&requeue_pool).await;
+                }
+                Err(e) => println!("Error requeuing expired leases: {}", e),
+            }
+        }
+    });
+
     HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
@@ -323,20 +874,16 @@ pub async fn run(host: String, port: u16, db: String, tokens: Vec<String>) -> st
             .max_age(3600);
         App::new()
             .wrap(cors)
-            .app_data(web::JsonConfig::default().limit(1024 * 1024 * 50))
             .app_data(web::PayloadConfig::new(1 << 25))
             .app_data(web::Data::new(Context {
                 pool: pool.clone(),
-                tokens: parsed_tokens.clone(),
+                lease_duration,
+                protect_reads,
+                max_log_size,
+                scheduling,
+                live: live.clone(),
             }))
-            .service(list_mutations)
-            .service(get_work)
-            .service(index)
-            .service(add_mutations)
-            .service(submit_mutation_result)
-            .service(get_mutation)
-            .service(list_mutations_files)
-            .service(count_mutations)
+            .configure(routes)
     })
     .bind(format!("{}:{}", host, port))
     .unwrap()
@@ -345,3 +892,193 @@ pub async fn run(host: String, port: u16, db: String, tokens: Vec<String>) -> st
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    use testing::{init_app, insert_pending, test_pool};
+
+    #[actix_web::test]
+    async fn test_get_work_never_hands_out_a_mutation_twice() {
+        let (pool, _db) = test_pool().await;
+        insert_pending(&pool, 20).await;
+
+        let app = init_app(&pool).await;
+
+        let requests = (0..50).map(|_| {
+            let req = test::TestRequest::post()
+                .uri("/get_work")
+                .insert_header(("Authorization", "secret"))
+                .to_request();
+            test::call_service(&app, req)
+        });
+        let responses = futures::future::join_all(requests).await;
+
+        let mut ids = vec![];
+        for res in responses {
+            if res.status() == 200 {
+                let mutation: Mutation = test::read_body_json(res).await;
+                assert_eq!(mutation.status, MutationStatus::Running.to_string());
+                assert_eq!(mutation.worker, Some("alice".to_string()));
+                ids.push(mutation.id);
+            } else {
+                assert_eq!(res.status(), 204);
+            }
+        }
+
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 20);
+    }
+
+    #[actix_web::test]
+    async fn test_expired_leases_are_requeued_then_errored() {
+        let (pool, _db) = test_pool().await;
+        insert_pending(&pool, 1).await;
+
+        let app = init_app(&pool).await;
+
+        for expected in [MutationStatus::Pending, MutationStatus::Error] {
+            let req = test::TestRequest::post()
+                .uri("/get_work")
+                .insert_header(("Authorization", "secret"))
+                .to_request();
+            let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+
+            // Nothing to do while the lease is valid.
+            assert!(requeue_expired_leases(&pool, 600, 2).await.unwrap().is_empty());
+
+            sqlx::query("UPDATE mutations SET lease_expires_at = 0 WHERE id = ?")
+                .bind(mutation.id)
+                .execute(&pool)
+                .await
+                .unwrap();
+            assert_eq!(requeue_expired_leases(&pool, 600, 2).await.unwrap().len(), 1);
+
+            let status: String = sqlx::query_scalar("SELECT status FROM mutations WHERE id = ?")
+                .bind(mutation.id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            assert_eq!(status, expected.to_string());
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_errors_are_returned_as_json() {
+        let (pool, _db) = test_pool().await;
+        insert_pending(&pool, 1).await;
+
+        let app = init_app(&pool).await;
+
+        let req = test::TestRequest::get().uri("/mutation/1").to_request();
+        let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(mutation.id, 1);
+
+        let req = test::TestRequest::get().uri("/mutation/42").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 404);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["error"], "not_found");
+        assert_eq!(body["message"], "Mutation 42 not found");
+
+        let req = test::TestRequest::get().uri("/mutation/abc").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 400);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["error"], "bad_request");
+
+        let req = test::TestRequest::post()
+            .uri("/mutations/1/heartbeat")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 401);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["error"], "unauthorized");
+
+        let req = test::TestRequest::post()
+            .uri("/mutations/1/heartbeat")
+            .insert_header(("Authorization", "Bearer secret"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 409);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["error"], "conflict");
+    }
+
+    #[actix_web::test]
+    async fn test_large_results_are_accepted() {
+        let (pool, _db) = test_pool().await;
+        insert_pending(&pool, 1).await;
+
+        let app = init_app(&pool).await;
+
+        let req = test::TestRequest::post()
+            .uri("/get_work")
+            .insert_header(("Authorization", "Bearer bob-worker"))
+            .to_request();
+        let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/mutations/{}", mutation.id))
+            .insert_header(("Authorization", "Bearer bob-worker"))
+            .set_json(MutationResult {
+                mutation_id: mutation.patch_md5.clone(),
+                status: MutationStatus::Killed,
+                stdout: Some("x".repeat(8 * 1024 * 1024)),
+                stderr: None,
+                exit_code: Some(1),
+            })
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn test_get_work_batches_share_a_checkout() {
+        let (pool, _db) = test_pool().await;
+        for (file, line, base_commit) in [
+            ("src/b.cpp", 1, "abc"),
+            ("src/a.cpp", 2, "def"),
+            ("src/a.cpp", 3, "abc"),
+            ("src/a.cpp", 1, "abc"),
+        ] {
+            sqlx::query(
+                "INSERT INTO mutations (patch_md5, file, line, patch, status, base_commit) VALUES (hex(randomblob(8)), ?, ?, '', ?, ?)",
+            )
+            .bind(file)
+            .bind(line)
+            .bind(MutationStatus::Pending.to_string())
+            .bind(base_commit)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let app = init_app(&pool).await;
+
+        let req = test::TestRequest::post()
+            .uri("/get_work?batch=0")
+            .insert_header(("Authorization", "Bearer bob-worker"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+
+        for expected in [vec![4, 3, 1], vec![2]] {
+            let req = test::TestRequest::post()
+                .uri("/get_work?batch=5")
+                .insert_header(("Authorization", "Bearer bob-worker"))
+                .to_request();
+            let batch: Vec<Mutation> = test::call_and_read_body_json(&app, req).await;
+            let ids: Vec<i64> = batch.iter().map(|m| m.id).collect();
+            assert_eq!(ids, expected);
+            assert!(batch.iter().all(|m| m.status == "Running"));
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/get_work?batch=5")
+            .insert_header(("Authorization", "Bearer bob-worker"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 204);
+    }
+}
@@ -0,0 +1,437 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use common::{Campaign, CampaignState, MutationStatus, NewCampaign};
+use sqlx::sqlite::SqlitePool;
+
+use super::capabilities;
+use super::tokens::{authorize, authorize_read, Role};
+use super::webhooks;
+use super::Context;
+use crate::errors::ServerError;
+
+/// A row of `campaigns`, whose requirements are stored as JSON.
+struct CampaignRow {
+    id: i64,
+    name: String,
+    base_commit: Option<String>,
+    source: String,
+    source_ref: Option<String>,
+    creator: String,
+    created_at: i64,
+    state: String,
+    priority: i64,
+    requires: String,
+}
+
+impl From<CampaignRow> for Campaign {
+    fn from(row: CampaignRow) -> Self {
+        Campaign {
+            id: row.id,
+            name: row.name,
+            base_commit: row.base_commit,
+            source: row.source,
+            source_ref: row.source_ref,
+            creator: row.creator,
+            created_at: row.created_at,
+            state: row.state,
+            priority: row.priority,
+            requires: capabilities::from_json(&row.requires),
+        }
+    }
+}
+
+/// Creates a campaign, or returns the existing one with the same name so
+/// uploading again adds to it. An existing campaign moves to the base commit
+/// given, so a later commit of the same files or PR re-anchors its pending
+/// mutations, but other settings that differ are a conflict.
+#[post("/campaigns")]
+async fn create_campaign(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    new_campaign: web::Json<NewCampaign>,
+) -> Result<HttpResponse, ServerError> {
+    let owner = authorize(&request, &ctx, Role::Submitter).await?;
+
+    let new_campaign = new_campaign.into_inner();
+    let now = chrono::Utc::now().timestamp();
+    let state = CampaignState::Open.to_string();
+    let priority = new_campaign.priority.unwrap_or_default();
+    let requires = capabilities::to_json(new_campaign.requires.as_deref().unwrap_or_default());
+    let r = sqlx::query!(
+        "INSERT INTO campaigns (name, base_commit, source, source_ref, creator, created_at, state, priority, requires) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT (name) DO NOTHING",
+        new_campaign.name,
+        new_campaign.base_commit,
+        new_campaign.source,
+        new_campaign.source_ref,
+        owner,
+        now,
+        state,
+        priority,
+        requires
+    )
+    .execute(&ctx.pool)
+    .await?;
+
+    let campaign: Campaign = sqlx::query_as!(
+        CampaignRow,
+        "SELECT * FROM campaigns WHERE name = ?",
+        new_campaign.name
+    )
+    .fetch_one(&ctx.pool)
+    .await?
+    .into();
+
+    if r.rows_affected() > 0 {
+        println!("Campaign {} created by {}", new_campaign.name, owner);
+        return Ok(HttpResponse::Ok().json(campaign));
+    }
+
+    if let Some(setting) = conflicting_setting(&campaign, &new_campaign) {
+        return Err(ServerError::Conflict(format!(
+            "Campaign {} already exists with another {}",
+            campaign.name, setting
+        )));
+    }
+
+    if new_campaign.base_commit.is_some() && new_campaign.base_commit != campaign.base_commit {
+        sqlx::query!(
+            "UPDATE campaigns SET base_commit = ? WHERE id = ?",
+            new_campaign.base_commit,
+            campaign.id
+        )
+        .execute(&ctx.pool)
+        .await?;
+        println!(
+            "Campaign {} moved to {:?} by {}",
+            campaign.name, new_campaign.base_commit, owner
+        );
+    }
+
+    let campaign = fetch_campaign(&ctx.pool, campaign.id).await?;
+    Ok(HttpResponse::Ok().json(campaign))
+}
+
+/// The setting of an upload that an existing campaign does not have. The
+/// priority and requirements of a campaign are only changed by admins, so
+/// they are refused rather than ignored.
+fn conflicting_setting(campaign: &Campaign, new_campaign: &NewCampaign) -> Option<&'static str> {
+    if new_campaign.source != campaign.source {
+        return Some("source");
+    }
+    // Runs of other files or another PR are not mixed into the campaign.
+    if new_campaign.source_ref.is_some() && new_campaign.source_ref != campaign.source_ref {
+        return Some("source_ref");
+    }
+    if new_campaign
+        .priority
+        .is_some_and(|priority| priority != campaign.priority)
+    {
+        return Some("priority");
+    }
+    if new_campaign
+        .requires
+        .as_ref()
+        .is_some_and(|requires| capabilities::normalize(requires) != campaign.requires)
+    {
+        return Some("requires");
+    }
+
+    None
+}
+
+#[get("/campaigns")]
+async fn list_campaigns(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+) -> Result<HttpResponse, ServerError> {
+    authorize_read(&request, &ctx).await?;
+
+    let campaigns: Vec<Campaign> =
+        sqlx::query_as!(CampaignRow, "SELECT * FROM campaigns ORDER BY id DESC")
+            .fetch_all(&ctx.pool)
+            .await?
+            .into_iter()
+            .map(Campaign::from)
+            .collect();
+
+    Ok(HttpResponse::Ok().json(campaigns))
+}
+
+#[get("/campaign/{id}")]
+async fn get_campaign(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ServerError> {
+    authorize_read(&request, &ctx).await?;
+
+    let id = id.into_inner();
+    let campaign = sqlx::query_as!(CampaignRow, "SELECT * FROM campaigns WHERE id = ?", id)
+        .fetch_optional(&ctx.pool)
+        .await?
+        .ok_or_else(|| ServerError::NotFound(format!("Campaign {} not found", id)))?;
+
+    Ok(HttpResponse::Ok().json(Campaign::from(campaign)))
+}
+
+/// Reads back a campaign known to exist.
+pub(super) async fn fetch_campaign(pool: &SqlitePool, id: i64) -> Result<Campaign, sqlx::Error> {
+    let campaign = sqlx::query_as!(CampaignRow, "SELECT * FROM campaigns WHERE id = ?", id)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(campaign.into())
+}
+
+/// The campaign mutations uploaded without one are added to.
+pub(super) async fn default_campaign_id(pool: &SqlitePool) -> Result<i64, sqlx::Error> {
+    let campaign = sqlx::query!("SELECT id FROM campaigns WHERE name = 'default'")
+        .fetch_one(pool)
+        .await?;
+
+    Ok(campaign.id)
+}
+
+pub(super) async fn campaign_exists(pool: &SqlitePool, id: i64) -> Result<bool, sqlx::Error> {
+    let campaign = sqlx::query!("SELECT id FROM campaigns WHERE id = ?", id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(campaign.is_some())
+}
+
+/// Marks campaigns whose mutations all ran as completed, and reopens
+/// completed ones that got new work. Empty and archived campaigns are left
+/// alone. Webhooks are told about the campaigns completed.
+pub(super) async fn refresh_states(pool: &SqlitePool) {
+    let state = "CASE
+            WHEN EXISTS (SELECT 1 FROM mutations WHERE campaign_id = campaigns.id AND status IN (?, ?)) THEN ?
+            WHEN EXISTS (SELECT 1 FROM mutations WHERE campaign_id = campaigns.id) THEN ?
+            ELSE state
+        END";
+    let pending = MutationStatus::Pending.to_string();
+    let running = MutationStatus::Running.to_string();
+    let open = CampaignState::Open.to_string();
+    let completed = CampaignState::Completed.to_string();
+    // Only campaigns whose state changes are returned.
+    let r = sqlx::query_as::<_, (i64, String)>(&format!(
+        "UPDATE campaigns SET state = {0} WHERE state != ? AND state != {0} RETURNING id, state",
+        state
+    ))
+    .bind(&pending)
+    .bind(&running)
+    .bind(&open)
+    .bind(&completed)
+    .bind(CampaignState::Archived.to_string())
+    .bind(&pending)
+    .bind(&running)
+    .bind(&open)
+    .bind(&completed)
+    .fetch_all(pool)
+    .await;
+
+    match r {
+        Ok(changed) => {
+            for (id, state) in changed {
+                if state == completed {
+                    println!("Campaign {} completed", id);
+                    webhooks::campaign_completed(pool, id).await;
+                }
+            }
+        }
+        Err(e) => println!("Error updating campaign states: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+    use common::{Mutation, MutationResult};
+
+    use crate::server::testing::{init_app, test_pool};
+
+    #[actix_web::test]
+    async fn test_mutations_are_scoped_by_campaign() {
+        let (pool, _db) = test_pool().await;
+        let app = init_app(&pool).await;
+
+        let mut campaigns = vec![];
+        for name in ["pr-1", "nightly"] {
+            let req = test::TestRequest::post()
+                .uri("/campaigns")
+                .insert_header(("Authorization", "Bearer carol-submitter"))
+                .set_json(common::NewCampaign {
+                    name: name.to_string(),
+                    base_commit: Some("abc".to_string()),
+                    source: "files".to_string(),
+                    source_ref: None,
+                    priority: None,
+                    requires: None,
+                })
+                .to_request();
+            let campaign: common::Campaign = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(campaign.creator, "carol");
+            campaigns.push(campaign.id);
+        }
+
+        // The same mutant is a separate mutation in each campaign.
+        for campaign in campaigns.iter().chain([campaigns[0], 999].iter()) {
+            let req = test::TestRequest::post()
+                .uri("/mutations")
+                .insert_header(("Authorization", "Bearer carol-submitter"))
+                .set_json(serde_json::json!([{
+                    "id": 0,
+                    "patch_md5": "md5",
+                    "file": "src/validation.cpp",
+                    "line": 1,
+                    "patch": "",
+                    "status": "Pending",
+                    "fingerprint": "fp",
+                    "campaign_id": campaign,
+                }]))
+                .to_request();
+            let expected = if *campaign == 999 { 400 } else { 200 };
+            assert_eq!(test::call_service(&app, req).await.status(), expected);
+        }
+
+        for (query, expected) in [
+            (String::new(), 2),
+            (format!("?campaign={}", campaigns[0]), 1),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/mutations/Pending/count{}", query))
+                .to_request();
+            let count: i64 = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(count, expected);
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/get_work")
+            .insert_header(("Authorization", "secret"))
+            .to_request();
+        let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+        let req = test::TestRequest::post()
+            .uri(&format!("/mutations/{}", mutation.id))
+            .insert_header(("Authorization", "secret"))
+            .set_json(MutationResult {
+                mutation_id: mutation.patch_md5.clone(),
+                status: MutationStatus::Killed,
+                stdout: None,
+                stderr: None,
+                exit_code: None,
+            })
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/campaign/{}", mutation.campaign_id.unwrap()))
+            .to_request();
+        let campaign: common::Campaign = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(campaign.state, common::CampaignState::Completed.to_string());
+    }
+
+    #[actix_web::test]
+    async fn test_uploads_for_a_later_commit_re_anchor_mutations() {
+        let (pool, _db) = test_pool().await;
+        let app = init_app(&pool).await;
+
+        let new_campaign = |base_commit: &str| common::NewCampaign {
+            name: "files".to_string(),
+            base_commit: Some(base_commit.to_string()),
+            source: "files".to_string(),
+            source_ref: Some("src/validation.cpp".to_string()),
+            priority: None,
+            requires: None,
+        };
+
+        // The same mutant, generated on two commits where its line moved.
+        let mut campaign_ids = vec![];
+        for (commit, md5, line) in [("c1", "md5-c1", 3), ("c2", "md5-c2", 5)] {
+            let req = test::TestRequest::post()
+                .uri("/campaigns")
+                .insert_header(("Authorization", "Bearer carol-submitter"))
+                .set_json(new_campaign(commit))
+                .to_request();
+            let campaign: common::Campaign = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(campaign.base_commit.as_deref(), Some(commit));
+            campaign_ids.push(campaign.id);
+
+            let req = test::TestRequest::post()
+                .uri("/mutations")
+                .insert_header(("Authorization", "Bearer carol-submitter"))
+                .set_json(serde_json::json!([{
+                    "id": 0,
+                    "patch_md5": md5,
+                    "file": "src/validation.cpp",
+                    "line": line,
+                    "patch": format!("patch on {}", commit),
+                    "status": "Pending",
+                    "fingerprint": "fp",
+                    "base_commit": commit,
+                    "campaign_id": campaign.id,
+                }]))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 200);
+        }
+        assert_eq!(campaign_ids[0], campaign_ids[1]);
+
+        let req = test::TestRequest::get()
+            .uri("/mutations/Pending/count")
+            .to_request();
+        let count: i64 = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(count, 1);
+
+        let req = test::TestRequest::get().uri("/mutation/1").to_request();
+        let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(mutation.patch_md5, "md5-c2");
+        assert_eq!(mutation.line, 5);
+        assert_eq!(mutation.base_commit.as_deref(), Some("c2"));
+
+        // Settings the campaign does not have are refused, not dropped.
+        for (campaign, expected) in [
+            (
+                common::NewCampaign {
+                    priority: Some(0),
+                    ..new_campaign("c2")
+                },
+                200,
+            ),
+            (
+                common::NewCampaign {
+                    priority: Some(10),
+                    ..new_campaign("c2")
+                },
+                409,
+            ),
+            (
+                common::NewCampaign {
+                    requires: Some(vec!["tsan".to_string()]),
+                    ..new_campaign("c2")
+                },
+                409,
+            ),
+            (
+                common::NewCampaign {
+                    source: "pr".to_string(),
+                    ..new_campaign("c2")
+                },
+                409,
+            ),
+            (
+                common::NewCampaign {
+                    source_ref: Some("src/net.cpp".to_string()),
+                    ..new_campaign("c2")
+                },
+                409,
+            ),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/campaigns")
+                .insert_header(("Authorization", "Bearer carol-submitter"))
+                .set_json(campaign)
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), expected);
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{QueryBuilder, Sqlite};
+
+use super::campaigns;
+use super::tokens::{authorize, Role};
+use super::{mutation_not_found, Context};
+use crate::errors::ServerError;
+
+#[derive(Debug, Deserialize)]
+struct NewRequirements {
+    requires: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct MutationRequirements {
+    id: i64,
+    requires: Vec<String>,
+}
+
+/// Trims, lowercases and dedupes capability tags so `TSan` and `tsan ` match.
+pub(super) fn normalize(tags: &[String]) -> Vec<String> {
+    let mut tags: Vec<String> = tags
+        .iter()
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+/// Tags are stored as a JSON array, the way workers store their capabilities.
+pub(super) fn to_json(tags: &[String]) -> String {
+    serde_json::to_string(&normalize(tags)).unwrap_or_else(|_| "[]".to_string())
+}
+
+pub(super) fn from_json(tags: &str) -> Vec<String> {
+    serde_json::from_str(tags).unwrap_or_default()
+}
+
+/// Appends a condition keeping the mutations aliased as `mutation` whose
+/// requirements, and those of their campaign, are all capabilities of the
+/// worker. Unregistered workers have none.
+pub(super) fn push_requirements_met(
+    query: &mut QueryBuilder<Sqlite>,
+    mutation: &str,
+    owner: &str,
+    worker_id: Option<i64>,
+) {
+    let push_missing = |query: &mut QueryBuilder<Sqlite>| {
+        query
+            .push(" value NOT IN (SELECT value FROM json_each((SELECT capabilities FROM workers WHERE id = ")
+            .push_bind(worker_id)
+            .push(" AND owner = ")
+            .push_bind(owner.to_string())
+            .push("))))");
+    };
+
+    query.push(format!(
+        " AND NOT EXISTS (SELECT 1 FROM json_each({}.requires) WHERE",
+        mutation
+    ));
+    push_missing(query);
+    query.push(format!(
+        " AND NOT EXISTS (SELECT 1 FROM campaigns rc, json_each(rc.requires) WHERE rc.id = {}.campaign_id AND",
+        mutation
+    ));
+    push_missing(query);
+}
+
+#[post("/campaign/{id}/requires")]
+async fn set_campaign_requirements(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+    requirements: web::Json<NewRequirements>,
+) -> Result<HttpResponse, ServerError> {
+    let admin = authorize(&request, &ctx, Role::Admin).await?;
+
+    let id = id.into_inner();
+    let requires = to_json(&requirements.requires);
+    let r = sqlx::query!(
+        "UPDATE campaigns SET requires = ? WHERE id = ?",
+        requires,
+        id
+    )
+    .execute(&ctx.pool)
+    .await?;
+    if r.rows_affected() == 0 {
+        return Err(ServerError::NotFound(format!("Campaign {} not found", id)));
+    }
+    println!(
+        "Campaign {} now requires {} (set by {})",
+        id, requires, admin
+    );
+
+    let campaign = campaigns::fetch_campaign(&ctx.pool, id).await?;
+    Ok(HttpResponse::Ok().json(campaign))
+}
+
+#[post("/mutation/{id}/requires")]
+async fn set_mutation_requirements(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+    requirements: web::Json<NewRequirements>,
+) -> Result<HttpResponse, ServerError> {
+    let admin = authorize(&request, &ctx, Role::Admin).await?;
+
+    let id = id.into_inner();
+    let requires = to_json(&requirements.requires);
+    let r = sqlx::query!(
+        "UPDATE mutations SET requires = ? WHERE id = ?",
+        requires,
+        id
+    )
+    .execute(&ctx.pool)
+    .await?;
+    if r.rows_affected() == 0 {
+        return Err(mutation_not_found(id));
+    }
+    println!(
+        "Mutation {} now requires {} (set by {})",
+        id, requires, admin
+    );
+
+    Ok(HttpResponse::Ok().json(MutationRequirements {
+        id,
+        requires: from_json(&requires),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test as actix_test;
+    use common::{Mutation, MutationStatus};
+
+    use crate::server::testing::{init_app, test_pool};
+
+    #[test]
+    fn test_tags_are_normalized() {
+        let tags = vec![
+            "TSan".to_string(),
+            " wallet".to_string(),
+            "tsan".to_string(),
+            "".to_string(),
+        ];
+        assert_eq!(normalize(&tags), ["tsan", "wallet"]);
+        assert_eq!(to_json(&tags), r#"["tsan","wallet"]"#);
+        assert_eq!(from_json(&to_json(&tags)), ["tsan", "wallet"]);
+    }
+
+    #[actix_web::test]
+    async fn test_get_work_matches_worker_capabilities() {
+        let (pool, _db) = test_pool().await;
+        let app = init_app(&pool).await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/campaigns")
+            .insert_header(("Authorization", "Bearer carol-submitter"))
+            .set_json(common::NewCampaign {
+                name: "gui".to_string(),
+                base_commit: None,
+                source: "files".to_string(),
+                source_ref: None,
+                priority: None,
+                requires: Some(vec!["Qt".to_string()]),
+            })
+            .to_request();
+        let gui: common::Campaign = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(gui.requires, ["qt"]);
+
+        for (requires, campaign) in [(r#"["tsan"]"#, "default"), ("[]", "default"), ("[]", "gui")] {
+            sqlx::query(
+                "INSERT INTO mutations (patch_md5, file, line, patch, status, requires, campaign_id) VALUES (hex(randomblob(8)), 'src/sync.h', 1, '', ?, ?, (SELECT id FROM campaigns WHERE name = ?))",
+            )
+            .bind(MutationStatus::Pending.to_string())
+            .bind(requires)
+            .bind(campaign)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let mut worker_ids = vec![];
+        for (token, capabilities) in [("bob-worker", vec!["TSan"]), ("secret", vec![])] {
+            let req = actix_test::TestRequest::post()
+                .uri("/workers")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(common::WorkerRegistration {
+                    hostname: "builder".to_string(),
+                    version: "0.1.0".to_string(),
+                    capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+                })
+                .to_request();
+            let worker: common::Worker = actix_test::call_and_read_body_json(&app, req).await;
+            worker_ids.push(worker.id);
+        }
+
+        let work_request = |token: &str, query: String| {
+            actix_test::TestRequest::post()
+                .uri(&format!("/get_work{}", query))
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .to_request()
+        };
+
+        // Unregistered workers have no capabilities.
+        let req = work_request("bob-worker", String::new());
+        let mutation: Mutation = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(mutation.id, 2);
+
+        let req = work_request("secret", format!("?worker_id={}", worker_ids[1]));
+        assert_eq!(actix_test::call_service(&app, req).await.status(), 204);
+
+        // Another owner's worker id does not lend its capabilities.
+        let req = work_request("secret", format!("?worker_id={}", worker_ids[0]));
+        assert_eq!(actix_test::call_service(&app, req).await.status(), 204);
+
+        let req = work_request("bob-worker", format!("?worker_id={}", worker_ids[0]));
+        let mutation: Mutation = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(mutation.id, 1);
+        assert_eq!(mutation.requires, ["tsan"]);
+
+        let req = work_request("bob-worker", format!("?worker_id={}", worker_ids[0]));
+        assert_eq!(actix_test::call_service(&app, req).await.status(), 204);
+
+        let req = actix_test::TestRequest::post()
+            .uri(&format!("/campaign/{}/requires", gui.id))
+            .insert_header(("Authorization", "Bearer root-admin"))
+            .set_json(serde_json::json!({"requires": []}))
+            .to_request();
+        let gui: common::Campaign = actix_test::call_and_read_body_json(&app, req).await;
+        assert!(gui.requires.is_empty());
+
+        let req = work_request("secret", format!("?worker_id={}", worker_ids[1]));
+        let mutation: Mutation = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(mutation.id, 3);
+    }
+}
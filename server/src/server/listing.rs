@@ -0,0 +1,210 @@
+use serde::Deserialize;
+use sqlx::{QueryBuilder, Sqlite};
+
+use super::tasks::TaskState;
+
+/// Filters shared by the endpoints selecting mutations. All are optional
+/// and combined with AND.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub(super) struct MutationFilter {
+    pub(super) campaign: Option<i64>,
+    pub(super) file: Option<String>,
+    pub(super) file_prefix: Option<String>,
+    pub(super) line_from: Option<i64>,
+    pub(super) line_to: Option<i64>,
+    pub(super) pr: Option<i64>,
+    pub(super) branch: Option<String>,
+    pub(super) mutator: Option<String>,
+    pub(super) worker: Option<String>,
+    /// Time window, in seconds since the epoch, on when the mutation finished
+    /// or, if it has not, started.
+    pub(super) since: Option<i64>,
+    pub(super) until: Option<i64>,
+    pub(super) task_state: Option<TaskState>,
+    pub(super) assignee: Option<String>,
+}
+
+impl MutationFilter {
+    /// Appends the filters as `AND` conditions to a query already holding a
+    /// `WHERE` clause.
+    pub(super) fn push_conditions(&self, query: &mut QueryBuilder<Sqlite>) {
+        if let Some(campaign) = self.campaign {
+            query.push(" AND campaign_id = ").push_bind(campaign);
+        }
+        if let Some(file) = &self.file {
+            query.push(" AND file = ").push_bind(file.clone());
+        }
+        if let Some(prefix) = &self.file_prefix {
+            query
+                .push(" AND substr(file, 1, length(")
+                .push_bind(prefix.clone())
+                .push(")) = ")
+                .push_bind(prefix.clone());
+        }
+        if let Some(line_from) = self.line_from {
+            query.push(" AND line >= ").push_bind(line_from);
+        }
+        if let Some(line_to) = self.line_to {
+            query.push(" AND line <= ").push_bind(line_to);
+        }
+        if let Some(pr) = self.pr {
+            query.push(" AND pr_number = ").push_bind(pr);
+        }
+        if let Some(branch) = &self.branch {
+            query.push(" AND branch = ").push_bind(branch.clone());
+        }
+        if let Some(mutator) = &self.mutator {
+            query.push(" AND mutator = ").push_bind(mutator.clone());
+        }
+        if let Some(worker) = &self.worker {
+            query.push(" AND worker = ").push_bind(worker.clone());
+        }
+        if let Some(since) = self.since {
+            query
+                .push(" AND COALESCE(end_time, start_time) >= ")
+                .push_bind(since);
+        }
+        if let Some(until) = self.until {
+            query
+                .push(" AND COALESCE(end_time, start_time) < ")
+                .push_bind(until);
+        }
+        if let Some(task_state) = self.task_state {
+            query
+                .push(" AND task_state = ")
+                .push_bind(task_state.to_string());
+        }
+        if let Some(assignee) = &self.assignee {
+            query.push(" AND assignee = ").push_bind(assignee.clone());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum SortField {
+    Id,
+    File,
+    Line,
+    StartTime,
+    EndTime,
+}
+
+impl SortField {
+    fn column(&self) -> &'static str {
+        match self {
+            SortField::Id => "id",
+            SortField::File => "file",
+            SortField::Line => "line",
+            SortField::StartTime => "start_time",
+            SortField::EndTime => "end_time",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum SortOrder {
+    Asc,
+    Desc,
+}
+
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Debug, Deserialize)]
+pub(super) struct Page {
+    sort: Option<SortField>,
+    order: Option<SortOrder>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    /// Patches are left out unless asked for, they make up most of the size
+    /// of a listing.
+    #[serde(default)]
+    pub(super) patch: bool,
+}
+
+impl Page {
+    pub(super) fn validate(&self) -> Result<(), String> {
+        if let Some(limit) = self.limit {
+            if !(1..=MAX_LIMIT).contains(&limit) {
+                return Err(format!("limit must be between 1 and {}", MAX_LIMIT));
+            }
+        }
+        if self.offset.unwrap_or(0) < 0 {
+            return Err("offset must not be negative".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Appends `ORDER BY`, `LIMIT` and `OFFSET`. Ties are broken by id so
+    /// pages do not overlap.
+    pub(super) fn push_clauses(&self, query: &mut QueryBuilder<Sqlite>) {
+        let sort = self.sort.unwrap_or(SortField::EndTime);
+        let order = match self.order.unwrap_or(SortOrder::Desc) {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        };
+
+        query
+            .push(format!(
+                " ORDER BY {} {}, id {}",
+                sort.column(),
+                order,
+                order
+            ))
+            .push(" LIMIT ")
+            .push_bind(self.limit.unwrap_or(100))
+            .push(" OFFSET ")
+            .push_bind(self.offset.unwrap_or(0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test;
+
+    use crate::server::testing::{init_app, insert_pending, test_pool};
+
+    #[actix_web::test]
+    async fn test_list_mutations_filters_and_pages() {
+        let (pool, _db) = test_pool().await;
+        insert_pending(&pool, 30).await;
+
+        let app = init_app(&pool).await;
+
+        let req = test::TestRequest::get()
+            .uri("/mutations/Pending?line_from=5&line_to=24&sort=line&order=asc&limit=8&offset=8")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get("X-Total-Count").unwrap(), "20");
+        let page: Vec<serde_json::Value> = test::read_body_json(res).await;
+        let lines: Vec<i64> = page.iter().map(|m| m["line"].as_i64().unwrap()).collect();
+        assert_eq!(lines, (13..21).collect::<Vec<i64>>());
+        assert!(page[0].get("patch").is_none());
+
+        let req = test::TestRequest::get()
+            .uri("/mutations/Pending?file_prefix=src/&patch=true&limit=1")
+            .to_request();
+        let page: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(page[0]["patch"], "");
+
+        for query in [
+            "line_from=abc",
+            "sort=patch",
+            "limit=0",
+            "limit=100000",
+            "offset=-1",
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/mutations/Pending?{}", query))
+                .to_request();
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                400,
+                "{}",
+                query
+            );
+        }
+    }
+}
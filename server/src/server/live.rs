@@ -0,0 +1,343 @@
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use common::MutationStatus;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{interval_at, Duration, Instant, Interval};
+
+use super::logs::Stream;
+use super::tokens::{authorize, authorize_read, Role};
+use super::workers;
+use super::{mutation_not_found, parse_query, Context};
+use crate::errors::ServerError;
+
+/// Events buffered for a subscriber that falls behind before it skips some.
+const CAPACITY: usize = 1024;
+
+/// Comments sent on idle feeds so proxies do not close them.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Broadcasts what happens to mutations while it happens: the output of
+/// running mutations and every status change. Nothing is kept, subscribers
+/// only get what is published after they subscribe.
+pub(super) struct Live {
+    statuses: broadcast::Sender<StatusEvent>,
+    logs: broadcast::Sender<LogChunk>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub(super) struct StatusEvent {
+    mutation_id: i64,
+    status: String,
+    worker: Option<String>,
+    time: i64,
+}
+
+#[derive(Clone, Debug)]
+struct LogChunk {
+    mutation_id: i64,
+    stream: Stream,
+    data: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LiveParams {
+    #[serde(default)]
+    stream: Stream,
+}
+
+impl Live {
+    pub(super) fn new() -> Self {
+        Live {
+            statuses: broadcast::channel(CAPACITY).0,
+            logs: broadcast::channel(CAPACITY).0,
+        }
+    }
+
+    pub(super) fn publish_status(&self, mutation_id: i64, status: &str, worker: Option<&str>) {
+        // Sending only fails when nobody is listening.
+        let _ = self.statuses.send(StatusEvent {
+            mutation_id,
+            status: status.to_string(),
+            worker: worker.map(str::to_string),
+            time: chrono::Utc::now().timestamp(),
+        });
+    }
+}
+
+fn status_frame(event: &StatusEvent) -> Bytes {
+    let data = serde_json::to_string(event).unwrap_or_default();
+    Bytes::from(format!("event: status\ndata: {}\n\n", data))
+}
+
+/// Every line of the chunk is a `data:` line, which SSE clients join back
+/// with newlines.
+fn log_frame(chunk: &LogChunk) -> Bytes {
+    let mut frame = format!("event: {}\n", chunk.stream);
+    for line in chunk.data.split('\n') {
+        frame.push_str("data: ");
+        frame.push_str(line.strip_suffix('\r').unwrap_or(line));
+        frame.push('\n');
+    }
+    frame.push('\n');
+    Bytes::from(frame)
+}
+
+fn keep_alive() -> Interval {
+    interval_at(Instant::now() + KEEP_ALIVE, KEEP_ALIVE)
+}
+
+fn event_stream<S>(stream: S) -> HttpResponse
+where
+    S: futures_util::Stream<Item = Result<Bytes, actix_web::Error>> + 'static,
+{
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "text/event-stream"))
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(stream)
+}
+
+/// Receives a chunk of the output of a running mutation from the worker it
+/// is leased to, as the plain text body.
+#[post("/mutation/{id}/live")]
+async fn publish_log(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+    body: Bytes,
+) -> Result<HttpResponse, ServerError> {
+    let owner = authorize(&request, &ctx, Role::Worker).await?;
+
+    let id = id.into_inner();
+    let params: LiveParams = parse_query(&request)?;
+    let running = MutationStatus::Running.to_string();
+    let worker_id = workers::worker_id(&request);
+    let leased = sqlx::query!(
+        "SELECT id FROM mutations WHERE id = ? AND status = ? AND worker = ? AND worker_id IS ?",
+        id,
+        running,
+        owner,
+        worker_id
+    )
+    .fetch_optional(&ctx.pool)
+    .await?;
+    if leased.is_none() {
+        return Err(ServerError::Conflict(format!(
+            "Mutation {} is not leased to you",
+            id
+        )));
+    }
+
+    let _ = ctx.live.logs.send(LogChunk {
+        mutation_id: id,
+        stream: params.stream,
+        data: String::from_utf8_lossy(&body).into_owned(),
+    });
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+struct LiveLog {
+    mutation_id: i64,
+    first: Option<Bytes>,
+    finished: bool,
+    logs: broadcast::Receiver<LogChunk>,
+    statuses: broadcast::Receiver<StatusEvent>,
+    keep_alive: Interval,
+}
+
+/// Follows the output of a mutation as Server-Sent Events: `stdout` and
+/// `stderr` events while it runs, and `status` events, starting with its
+/// current status. The feed ends once the mutation is no longer running.
+#[get("/mutation/{id}/live")]
+async fn follow_log(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ServerError> {
+    authorize_read(&request, &ctx).await?;
+
+    let id = id.into_inner();
+    // Subscribe first so nothing published while the status is read is lost.
+    let logs = ctx.live.logs.subscribe();
+    let statuses = ctx.live.statuses.subscribe();
+    let mutation = sqlx::query!("SELECT status, worker FROM mutations WHERE id = ?", id)
+        .fetch_optional(&ctx.pool)
+        .await?
+        .ok_or_else(|| mutation_not_found(id))?;
+
+    let running = MutationStatus::Running.to_string();
+    let state = LiveLog {
+        mutation_id: id,
+        finished: mutation.status != running,
+        first: Some(status_frame(&StatusEvent {
+            mutation_id: id,
+            status: mutation.status,
+            worker: mutation.worker,
+            time: chrono::Utc::now().timestamp(),
+        })),
+        logs,
+        statuses,
+        keep_alive: keep_alive(),
+    };
+
+    let stream = futures_util::stream::unfold(state, move |mut state| {
+        let running = running.clone();
+        async move {
+            if let Some(frame) = state.first.take() {
+                return Some((Ok(frame), state));
+            }
+            if state.finished {
+                return None;
+            }
+
+            loop {
+                // Output is sent before the result, so drain it before a
+                // status that would end the feed.
+                let frame = tokio::select! {
+                    biased;
+                    chunk = state.logs.recv() => match chunk {
+                        Ok(chunk) if chunk.mutation_id == state.mutation_id => log_frame(&chunk),
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(n)) => {
+                            Bytes::from(format!(": {} chunks skipped\n\n", n))
+                        }
+                        Err(RecvError::Closed) => return None,
+                    },
+                    event = state.statuses.recv() => match event {
+                        Ok(event) if event.mutation_id == state.mutation_id => {
+                            state.finished = event.status != running;
+                            status_frame(&event)
+                        }
+                        Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return None,
+                    },
+                    _ = state.keep_alive.tick() => Bytes::from_static(b": keep-alive\n\n"),
+                };
+                return Some((Ok(frame), state));
+            }
+        }
+    });
+
+    Ok(event_stream(stream))
+}
+
+/// Every status change of every mutation as Server-Sent Events, for
+/// dashboards to update without polling.
+#[get("/events")]
+async fn events(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+) -> Result<HttpResponse, ServerError> {
+    authorize_read(&request, &ctx).await?;
+
+    let state = (ctx.live.statuses.subscribe(), keep_alive());
+    let stream = futures_util::stream::unfold(state, |(mut statuses, mut keep_alive)| async move {
+        let frame = tokio::select! {
+            event = statuses.recv() => match event {
+                Ok(event) => status_frame(&event),
+                Err(RecvError::Lagged(n)) => Bytes::from(format!(": {} events skipped\n\n", n)),
+                Err(RecvError::Closed) => return None,
+            },
+            _ = keep_alive.tick() => Bytes::from_static(b": keep-alive\n\n"),
+        };
+        Some((Ok(frame), (statuses, keep_alive)))
+    });
+
+    Ok(event_stream(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test as actix_test;
+    use common::{Mutation, MutationResult};
+
+    use crate::server::testing::{init_app, insert_pending, test_pool};
+
+    #[test]
+    fn test_log_frames_keep_every_line() {
+        let chunk = LogChunk {
+            mutation_id: 1,
+            stream: Stream::Stderr,
+            data: "make: *** [all] Error 2\r\nretrying\n".to_string(),
+        };
+        assert_eq!(
+            log_frame(&chunk),
+            "event: stderr\ndata: make: *** [all] Error 2\ndata: retrying\ndata: \n\n"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_live_output_and_events_are_streamed() {
+        use actix_web::body::MessageBody;
+
+        let (pool, _db) = test_pool().await;
+        insert_pending(&pool, 1).await;
+        let app = init_app(&pool).await;
+
+        let req = actix_test::TestRequest::get().uri("/events").to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert_eq!(
+            res.headers().get("Content-Type").unwrap(),
+            "text/event-stream"
+        );
+        let mut statuses = Box::pin(res.into_body());
+
+        let req = actix_test::TestRequest::post()
+            .uri("/get_work")
+            .insert_header(("Authorization", "Bearer bob-worker"))
+            .to_request();
+        let mutation: Mutation = actix_test::call_and_read_body_json(&app, req).await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/mutation/1/live")
+            .to_request();
+        let live = actix_test::call_service(&app, req).await;
+
+        for (token, status) in [("bob-worker", 200), ("secret", 409)] {
+            let req = actix_test::TestRequest::post()
+                .uri("/mutation/1/live?stream=stderr")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_payload("compiling\nlinking\n")
+                .to_request();
+            assert_eq!(actix_test::call_service(&app, req).await.status(), status);
+        }
+
+        let req = actix_test::TestRequest::post()
+            .uri("/mutations/1")
+            .insert_header(("Authorization", "Bearer bob-worker"))
+            .set_json(MutationResult {
+                mutation_id: mutation.patch_md5.clone(),
+                status: MutationStatus::Killed,
+                stdout: None,
+                stderr: None,
+                exit_code: Some(2),
+            })
+            .to_request();
+        assert_eq!(actix_test::call_service(&app, req).await.status(), 200);
+
+        // The feed ends with the result.
+        let live = actix_web::body::to_bytes(live.into_body()).await.unwrap();
+        let live = String::from_utf8(live.to_vec()).unwrap();
+        let frames: Vec<&str> = live.split_terminator("\n\n").collect();
+        assert_eq!(frames.len(), 3, "{}", live);
+        assert!(frames[0].contains(r#""status":"Running""#));
+        assert_eq!(
+            frames[1],
+            "event: stderr\ndata: compiling\ndata: linking\ndata: "
+        );
+        assert!(frames[2].contains(r#""status":"Killed""#));
+
+        for status in ["Running", "Killed"] {
+            let frame = std::future::poll_fn(|cx| statuses.as_mut().poll_next(cx))
+                .await
+                .unwrap()
+                .unwrap();
+            let frame = String::from_utf8(frame.to_vec()).unwrap();
+            assert!(frame.starts_with("event: status\n"), "{}", frame);
+            assert!(frame.contains(&format!(r#""status":"{}""#, status)));
+        }
+    }
+}
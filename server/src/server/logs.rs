@@ -0,0 +1,319 @@
+use actix_web::http::header;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use sqlx::sqlite::{Sqlite, SqlitePool};
+use sqlx::Transaction;
+
+use super::tokens::authorize_read;
+use super::{mutation_not_found, parse_query, Context};
+use crate::errors::ServerError;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum Stream {
+    #[default]
+    Stdout,
+    Stderr,
+}
+
+impl std::fmt::Display for Stream {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Stream::Stdout => write!(f, "stdout"),
+            Stream::Stderr => write!(f, "stderr"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LogParams {
+    /// Defaults to the latest attempt.
+    attempt: Option<i64>,
+    #[serde(default)]
+    stream: Stream,
+}
+
+/// Keeps the first and last `max_size / 2` bytes of a log longer than
+/// `max_size`, where build and test failures usually are.
+fn truncate(log: &[u8], max_size: usize) -> Vec<u8> {
+    if log.len() <= max_size {
+        return log.to_vec();
+    }
+
+    let half = max_size / 2;
+    let mut truncated = log[..half].to_vec();
+    truncated.extend_from_slice(
+        format!("\n[... {} bytes truncated ...]\n", log.len() - 2 * half).as_bytes(),
+    );
+    truncated.extend_from_slice(&log[log.len() - half..]);
+    truncated
+}
+
+/// Stores the log of an attempt compressed, truncated to `max_size` bytes.
+pub(super) async fn store_log(
+    tx: &mut Transaction<'_, Sqlite>,
+    result_id: i64,
+    stream: Stream,
+    log: &str,
+    max_size: usize,
+) -> Result<(), ServerError> {
+    let original_size = log.len() as i64;
+    let truncated = log.len() > max_size;
+    let data = zstd::encode_all(&truncate(log.as_bytes(), max_size)[..], 0)
+        .map_err(|e| ServerError::Internal(format!("Error compressing log: {}", e)))?;
+    let stream = stream.to_string();
+
+    sqlx::query!(
+        "INSERT INTO logs (result_id, stream, data, original_size, truncated) VALUES (?, ?, ?, ?, ?)",
+        result_id,
+        stream,
+        data,
+        original_size,
+        truncated
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Returns the log of an attempt, decompressed. Attempts recorded before logs
+/// were compressed still have them as text.
+pub(super) async fn load_log(
+    pool: &SqlitePool,
+    result_id: i64,
+    stream: Stream,
+) -> Result<Option<Vec<u8>>, ServerError> {
+    let name = stream.to_string();
+    let compressed = sqlx::query!(
+        "SELECT data FROM logs WHERE result_id = ? AND stream = ?",
+        result_id,
+        name
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    if let Some(compressed) = compressed {
+        let log = zstd::decode_all(&compressed.data[..])
+            .map_err(|e| ServerError::Internal(format!("Error decompressing log: {}", e)))?;
+        return Ok(Some(log));
+    }
+
+    let legacy = sqlx::query!("SELECT stdout, stderr FROM results WHERE id = ?", result_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(legacy
+        .and_then(|legacy| match stream {
+            Stream::Stdout => legacy.stdout,
+            Stream::Stderr => legacy.stderr,
+        })
+        .map(String::into_bytes))
+}
+
+/// Parses a single `bytes=` range into inclusive bounds. Other units and
+/// multiple ranges are ignored and the whole log is sent, as RFC 9110 allows.
+fn parse_range(range: &str, len: usize) -> Result<Option<(usize, usize)>, ServerError> {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return Ok(None),
+    };
+
+    let bounds = match (start.parse::<usize>(), end.parse::<usize>()) {
+        (Ok(start), Ok(end)) if start <= end => Some((start, end.min(len.saturating_sub(1)))),
+        (Ok(start), Err(_)) if end.is_empty() => Some((start, len.saturating_sub(1))),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            Some((len.saturating_sub(suffix), len.saturating_sub(1)))
+        }
+        _ => return Ok(None),
+    };
+
+    match bounds {
+        Some((start, _)) if start >= len => Err(ServerError::RangeNotSatisfiable(len)),
+        bounds => Ok(bounds),
+    }
+}
+
+/// Sends the log of an attempt of a mutation as plain text. The stored log is
+/// capped by `--max-log-size`, so it is decompressed in memory before the
+/// requested range is sent.
+#[get("/mutation/{id}/log")]
+async fn get_log(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ServerError> {
+    authorize_read(&request, &ctx).await?;
+
+    let id = id.into_inner();
+    let params: LogParams = parse_query(&request)?;
+
+    // The latest attempt that was not requeued, or the latest one.
+    let attempt = sqlx::query!(
+        "SELECT id AS \"id!\" FROM results WHERE mutation_id = ? AND (? IS NULL OR id = ?) ORDER BY requeued_at IS NULL DESC, id DESC LIMIT 1",
+        id,
+        params.attempt,
+        params.attempt
+    )
+    .fetch_optional(&ctx.pool)
+    .await?;
+
+    let log = match (attempt, params.attempt) {
+        (Some(attempt), _) => load_log(&ctx.pool, attempt.id, params.stream).await?,
+        (None, Some(attempt)) => {
+            return Err(ServerError::NotFound(format!(
+                "Attempt {} of mutation {} not found",
+                attempt, id
+            )))
+        }
+        // Mutations that ran before attempts were recorded.
+        (None, None) => {
+            let mutation = sqlx::query!("SELECT stdout, stderr FROM mutations WHERE id = ?", id)
+                .fetch_optional(&ctx.pool)
+                .await?
+                .ok_or_else(|| mutation_not_found(id))?;
+            match params.stream {
+                Stream::Stdout => mutation.stdout,
+                Stream::Stderr => mutation.stderr,
+            }
+            .map(String::into_bytes)
+        }
+    };
+    let log = log.ok_or_else(|| {
+        ServerError::NotFound(format!("No {} log for mutation {}", params.stream, id))
+    })?;
+
+    let range = match request.headers().get(header::RANGE) {
+        Some(range) => parse_range(range.to_str().unwrap_or_default(), log.len())?,
+        None => None,
+    };
+
+    let (mut response, body) = match range {
+        Some((start, end)) => {
+            let mut response = HttpResponse::PartialContent();
+            response.insert_header((
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, log.len()),
+            ));
+            (response, log[start..=end].to_vec())
+        }
+        None => (HttpResponse::Ok(), log),
+    };
+
+    Ok(response
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .content_type("text/plain; charset=utf-8")
+        .body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test as actix_test;
+    use common::{Mutation, MutationResult, MutationStatus};
+
+    use crate::server::testing::{init_app_with, insert_pending, test_context, test_pool};
+
+    #[test]
+    fn test_truncate_keeps_head_and_tail() {
+        assert_eq!(truncate(b"short", 10), b"short");
+
+        let log = format!("{}{}{}", "h".repeat(5), "m".repeat(100), "t".repeat(5));
+        let truncated = String::from_utf8(truncate(log.as_bytes(), 10)).unwrap();
+        assert_eq!(truncated, "hhhhh\n[... 100 bytes truncated ...]\nttttt");
+    }
+
+    #[test]
+    fn test_parse_range() {
+        for (range, expected) in [
+            ("bytes=0-9", Some((0, 9))),
+            ("bytes=90-200", Some((90, 99))),
+            ("bytes=95-", Some((95, 99))),
+            ("bytes=-10", Some((90, 99))),
+            ("bytes=-500", Some((0, 99))),
+            ("bytes=0-1,5-6", None),
+            ("lines=0-1", None),
+            ("bytes=5-1", None),
+        ] {
+            assert_eq!(parse_range(range, 100).unwrap(), expected, "{}", range);
+        }
+
+        assert!(matches!(
+            parse_range("bytes=100-", 100),
+            Err(ServerError::RangeNotSatisfiable(100))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_logs_are_truncated_and_served_by_range() {
+        let (pool, _db) = test_pool().await;
+        insert_pending(&pool, 1).await;
+
+        let mut ctx = test_context(&pool).await;
+        ctx.max_log_size = 20;
+        let app = init_app_with(ctx).await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/get_work")
+            .insert_header(("Authorization", "Bearer bob-worker"))
+            .to_request();
+        let mutation: Mutation = actix_test::call_and_read_body_json(&app, req).await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/mutations/1")
+            .insert_header(("Authorization", "Bearer bob-worker"))
+            .set_json(MutationResult {
+                mutation_id: mutation.patch_md5.clone(),
+                status: MutationStatus::Killed,
+                stdout: Some(format!(
+                    "{}{}{}",
+                    "h".repeat(10),
+                    "m".repeat(1000),
+                    "t".repeat(10)
+                )),
+                stderr: Some("boom".to_string()),
+                exit_code: Some(1),
+            })
+            .to_request();
+        assert_eq!(actix_test::call_service(&app, req).await.status(), 200);
+
+        let stdout = "hhhhhhhhhh\n[... 1000 bytes truncated ...]\ntttttttttt";
+        let req = actix_test::TestRequest::get()
+            .uri("/mutation/1/log")
+            .to_request();
+        assert_eq!(actix_test::call_and_read_body(&app, req).await, stdout);
+
+        let req = actix_test::TestRequest::get()
+            .uri("/mutation/1")
+            .to_request();
+        let mutation: Mutation = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(mutation.stdout.as_deref(), Some(stdout));
+        assert_eq!(mutation.stderr.as_deref(), Some("boom"));
+
+        let req = actix_test::TestRequest::get()
+            .uri("/mutation/1/log?stream=stderr")
+            .insert_header(("Range", "bytes=-2"))
+            .to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert_eq!(res.status(), 206);
+        assert_eq!(res.headers().get("Content-Range").unwrap(), "bytes 2-3/4");
+        assert_eq!(actix_test::read_body(res).await, "om");
+
+        let req = actix_test::TestRequest::get()
+            .uri("/mutation/1/log?stream=stderr")
+            .insert_header(("Range", "bytes=4-"))
+            .to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert_eq!(res.status(), 416);
+        assert_eq!(res.headers().get("Content-Range").unwrap(), "bytes */4");
+
+        let req = actix_test::TestRequest::get()
+            .uri("/mutation/1/log?attempt=99")
+            .to_request();
+        assert_eq!(actix_test::call_service(&app, req).await.status(), 404);
+    }
+}
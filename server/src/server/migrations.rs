@@ -0,0 +1,124 @@
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::SqlitePool;
+
+/// The migrations in `migrations/`, embedded at build time. Every schema
+/// change, including new columns, ships as a new file there.
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// Brings the database up to date before the server starts serving.
+///
+/// Databases created by hand from the initial migration, before migrations
+/// were applied on startup, have a `mutations` table but no record of it, so
+/// that migration is recorded as applied first. A database that has already
+/// seen migrations this binary does not know about was written by a newer
+/// server and is left untouched.
+pub(super) async fn migrate(pool: &SqlitePool) -> Result<(), String> {
+    let tracked = table_exists(pool, "_sqlx_migrations").await?;
+
+    if tracked {
+        let applied: Vec<i64> = sqlx::query_scalar("SELECT version FROM _sqlx_migrations")
+            .fetch_all(pool)
+            .await
+            .map_err(|e| format!("Error reading applied migrations: {}", e))?;
+
+        let unknown: Vec<i64> = applied
+            .into_iter()
+            .filter(|v| !MIGRATOR.iter().any(|m| m.version == *v))
+            .collect();
+        if !unknown.is_empty() {
+            return Err(format!(
+                "Database schema has migrations unknown to this server ({:?}), refusing to start. Upgrade the server.",
+                unknown
+            ));
+        }
+    } else if table_exists(pool, "mutations").await? {
+        baseline(pool).await?;
+    }
+
+    MIGRATOR
+        .run(pool)
+        .await
+        .map_err(|e| format!("Error applying migrations: {}", e))
+}
+
+async fn table_exists(pool: &SqlitePool, name: &str) -> Result<bool, String> {
+    let count: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?")
+            .bind(name)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| format!("Error inspecting database: {}", e))?;
+
+    Ok(count > 0)
+}
+
+async fn baseline(pool: &SqlitePool) -> Result<(), String> {
+    let init = MIGRATOR.iter().next().expect("no migrations embedded");
+    println!(
+        "Existing database without migration history, recording migration {} as applied",
+        init.version
+    );
+
+    sqlx::query(
+        "CREATE TABLE _sqlx_migrations (
+            version BIGINT PRIMARY KEY,
+            description TEXT NOT NULL,
+            installed_on TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            success BOOLEAN NOT NULL,
+            checksum BLOB NOT NULL,
+            execution_time BIGINT NOT NULL
+        )",
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| format!("Error creating migration table: {}", e))?;
+
+    sqlx::query(
+        "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time) VALUES (?, ?, TRUE, ?, -1)",
+    )
+    .bind(init.version)
+    .bind(&*init.description)
+    .bind(&*init.checksum)
+    .execute(pool)
+    .await
+    .map_err(|e| format!("Error recording migration: {}", e))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::server::testing::empty_pool;
+
+    #[actix_web::test]
+    async fn test_migrate_adopts_hand_created_database() {
+        let (pool, _db) = empty_pool().await;
+        let init = MIGRATOR.iter().next().unwrap();
+        sqlx::query(&init.sql).execute(&pool).await.unwrap();
+
+        migrate(&pool).await.unwrap();
+
+        // Columns added by later migrations are there.
+        sqlx::query("SELECT fingerprint, lease_expires_at FROM mutations")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_migrate_refuses_newer_schema() {
+        let (pool, _db) = empty_pool().await;
+        migrate(&pool).await.unwrap();
+
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time) VALUES (99990101000000, 'future', TRUE, x'00', 0)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert!(migrate(&pool).await.unwrap_err().contains("99990101000000"));
+    }
+}
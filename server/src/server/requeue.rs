@@ -0,0 +1,283 @@
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use common::MutationStatus;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqlitePool;
+use sqlx::{QueryBuilder, Sqlite};
+
+use super::listing::MutationFilter;
+use super::tokens::{authorize, Role};
+use super::{campaigns, fetch_mutation, mutation_not_found, Context};
+use crate::errors::ServerError;
+
+#[derive(Debug, Deserialize)]
+struct RequeueFilter {
+    /// Without a status, every mutation with a result matches. Triaged and
+    /// running mutations are only requeued when their status is given.
+    status: Option<MutationStatus>,
+    #[serde(flatten)]
+    filter: MutationFilter,
+}
+
+#[derive(Debug, Serialize)]
+struct Requeued {
+    requeued: u64,
+}
+
+/// Statuses reported by workers, which a requeue without a status applies to.
+const RESULT_STATUSES: [MutationStatus; 5] = [
+    MutationStatus::Killed,
+    MutationStatus::NotKilled,
+    MutationStatus::Timeout,
+    MutationStatus::Error,
+    MutationStatus::Stale,
+];
+
+/// The mutations a requeue applies to.
+enum Selection<'a> {
+    One(i64),
+    Matching(&'a RequeueFilter),
+}
+
+impl Selection<'_> {
+    /// Appends the condition selecting the mutations after a `WHERE`.
+    fn push_condition(&self, query: &mut QueryBuilder<Sqlite>) {
+        match self {
+            // Retired mutations no longer apply to the code.
+            Selection::One(id) => {
+                query
+                    .push("id = ")
+                    .push_bind(*id)
+                    .push(" AND status != ")
+                    .push_bind(MutationStatus::Retired.to_string());
+            }
+            Selection::Matching(requeue) => {
+                match &requeue.status {
+                    Some(status) => {
+                        query.push("status = ").push_bind(status.to_string());
+                    }
+                    None => {
+                        let mut statuses = query.push("status IN (").separated(", ");
+                        for status in &RESULT_STATUSES {
+                            statuses.push_bind(status.to_string());
+                        }
+                        statuses.push_unseparated(")");
+                    }
+                }
+                requeue.filter.push_conditions(query);
+            }
+        }
+    }
+}
+
+/// Puts the selected mutations back in the queue and returns their ids. Their
+/// attempts are marked as requeued, and results reported before attempts were
+/// recorded are moved to the `results` table.
+async fn requeue(
+    pool: &SqlitePool,
+    selection: Selection<'_>,
+    admin: &str,
+) -> Result<Vec<i64>, sqlx::Error> {
+    let pending = MutationStatus::Pending.to_string();
+    let now = chrono::Utc::now().timestamp();
+    let mut tx = pool.begin().await?;
+
+    let mut mark = QueryBuilder::new("UPDATE results SET requeued_at = ");
+    mark.push_bind(now)
+        .push(", requeued_by = ")
+        .push_bind(admin.to_string())
+        .push(" WHERE requeued_at IS NULL AND mutation_id IN (SELECT id FROM mutations WHERE ");
+    selection.push_condition(&mut mark);
+    mark.push(")");
+    mark.build().execute(&mut tx).await?;
+
+    let mut archive = QueryBuilder::new(
+        "INSERT INTO results (mutation_id, status, worker, start_time, end_time, stdout, stderr, requeued_at, requeued_by) SELECT id, status, worker, start_time, end_time, stdout, stderr, ",
+    );
+    archive
+        .push_bind(now)
+        .push(", ")
+        .push_bind(admin.to_string())
+        .push(" FROM mutations WHERE (stdout IS NOT NULL OR stderr IS NOT NULL) AND ");
+    selection.push_condition(&mut archive);
+    archive.build().execute(&mut tx).await?;
+
+    let mut release = QueryBuilder::new(
+        "UPDATE workers SET current_mutation = NULL WHERE current_mutation IN (SELECT id FROM mutations WHERE status = ",
+    );
+    release
+        .push_bind(MutationStatus::Running.to_string())
+        .push(" AND ");
+    selection.push_condition(&mut release);
+    release.push(")");
+    release.build().execute(&mut tx).await?;
+
+    let mut reset = QueryBuilder::new("UPDATE mutations SET status = ");
+    reset.push_bind(&pending).push(
+        ", start_time = NULL, end_time = NULL, stdout = NULL, stderr = NULL, worker = NULL, worker_id = NULL, lease_expires_at = NULL, attempts = 0 WHERE ",
+    );
+    selection.push_condition(&mut reset);
+    reset.push(" RETURNING id");
+    let requeued: Vec<(i64,)> = reset.build_query_as().fetch_all(&mut tx).await?;
+
+    tx.commit().await?;
+    Ok(requeued.into_iter().map(|(id,)| id).collect())
+}
+
+fn publish(ctx: &Context, requeued: &[i64]) {
+    let pending = MutationStatus::Pending.to_string();
+    for id in requeued {
+        ctx.live.publish_status(*id, &pending, None);
+    }
+}
+
+#[post("/mutation/{id}/requeue")]
+async fn requeue_mutation(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ServerError> {
+    let admin = authorize(&request, &ctx, Role::Admin).await?;
+
+    let id = id.into_inner();
+    let requeued = requeue(&ctx.pool, Selection::One(id), &admin).await?;
+    campaigns::refresh_states(&ctx.pool).await;
+
+    if requeued.is_empty() {
+        return match fetch_mutation(&ctx.pool, id).await? {
+            Some(_) => Err(ServerError::Conflict(format!("Mutation {} is retired", id))),
+            None => Err(mutation_not_found(id)),
+        };
+    }
+    publish(&ctx, &requeued);
+
+    Ok(HttpResponse::Ok().body("Mutation requeued"))
+}
+
+/// Requeues every mutation matching the filters, e.g. all results from a
+/// broken worker: `{"worker": "alice", "since": 1678000000}`.
+#[post("/mutations/requeue")]
+async fn requeue_mutations(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    filter: web::Json<RequeueFilter>,
+) -> Result<HttpResponse, ServerError> {
+    let admin = authorize(&request, &ctx, Role::Admin).await?;
+
+    let filter = filter.into_inner();
+    if filter.status.is_none() && filter.filter == MutationFilter::default() {
+        return Err(ServerError::BadRequest(
+            "Give at least one filter to requeue everything matching it".to_string(),
+        ));
+    }
+
+    let requeued = requeue(&ctx.pool, Selection::Matching(&filter), &admin).await?;
+    campaigns::refresh_states(&ctx.pool).await;
+    publish(&ctx, &requeued);
+    println!("{} mutations requeued by {}", requeued.len(), admin);
+
+    Ok(HttpResponse::Ok().json(Requeued {
+        requeued: requeued.len() as u64,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+    use common::Mutation;
+
+    use crate::server::testing::{init_app, insert_pending, test_pool};
+
+    #[actix_web::test]
+    async fn test_requeue_by_filter_keeps_previous_results() {
+        let (pool, _db) = test_pool().await;
+        insert_pending(&pool, 6).await;
+        for (id, status, worker) in [
+            (1, MutationStatus::Killed, "alice"),
+            (2, MutationStatus::NotKilled, "alice"),
+            (3, MutationStatus::NotKilled, "bob"),
+            (4, MutationStatus::Retired, "alice"),
+            (5, MutationStatus::Ignored, "alice"),
+            (6, MutationStatus::Running, "alice"),
+        ] {
+            sqlx::query(
+                "UPDATE mutations SET status = ?, worker = ?, start_time = 10, end_time = 20, stdout = 'ok' WHERE id = ?",
+            )
+            .bind(status.to_string())
+            .bind(worker)
+            .bind(id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let app = init_app(&pool).await;
+
+        for (token, filter, expected) in [
+            ("secret", serde_json::json!({"worker": "alice"}), None),
+            ("root-admin", serde_json::json!({}), None),
+            (
+                "root-admin",
+                serde_json::json!({"worker": "alice"}),
+                Some(2),
+            ),
+            (
+                "root-admin",
+                serde_json::json!({"status": "NotKilled"}),
+                Some(1),
+            ),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/mutations/requeue")
+                .insert_header(("Authorization", token))
+                .set_json(&filter)
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            match expected {
+                Some(expected) => {
+                    let body: serde_json::Value = test::read_body_json(res).await;
+                    assert_eq!(body["requeued"], expected, "{}", filter);
+                }
+                None => assert!(res.status().is_client_error(), "{}", filter),
+            }
+        }
+
+        // Retired, triaged and running mutations are left alone.
+        for (id, status) in [(4, "Retired"), (5, "Ignored"), (6, "Running")] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/mutation/{}", id))
+                .to_request();
+            let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(mutation.status, status);
+        }
+        let req = test::TestRequest::post()
+            .uri("/mutation/4/requeue")
+            .insert_header(("Authorization", "root-admin"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 409);
+
+        let req = test::TestRequest::get()
+            .uri("/mutations/Pending/count")
+            .to_request();
+        let count: i64 = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(count, 3);
+
+        let req = test::TestRequest::get()
+            .uri("/mutation/1/results")
+            .to_request();
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["status"], "Killed");
+        assert_eq!(results[0]["requeued_by"], "root");
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/mutation/1/log?attempt={}", results[0]["id"]))
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "ok");
+
+        let req = test::TestRequest::get().uri("/mutation/1").to_request();
+        let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(mutation.stdout, None);
+        assert_eq!(mutation.worker, None);
+    }
+}
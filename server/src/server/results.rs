@@ -0,0 +1,255 @@
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use common::{Mutation, MutationResult, MutationStatus};
+use serde::Serialize;
+use sqlx::sqlite::SqlitePool;
+use sqlx::QueryBuilder;
+
+use super::listing::MutationFilter;
+use super::logs::{self, Stream};
+use super::tokens::authorize_read;
+use super::{mutation_not_found, parse_query, Context};
+use crate::errors::ServerError;
+
+/// One execution of a mutation, as reported by a worker.
+#[derive(Debug, Serialize)]
+struct Attempt {
+    id: i64,
+    mutation_id: i64,
+    status: String,
+    worker: Option<String>,
+    worker_id: Option<i64>,
+    start_time: Option<i64>,
+    end_time: Option<i64>,
+    exit_code: Option<i64>,
+    requeued_at: Option<i64>,
+    requeued_by: Option<String>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct FlakyMutation {
+    id: i64,
+    file: String,
+    line: i64,
+    status: String,
+    campaign_id: Option<i64>,
+    fingerprint: Option<String>,
+    runs: i64,
+    killed: i64,
+    not_killed: i64,
+}
+
+/// Stores the result of a worker as a new attempt, and gives the mutation the
+/// status of its latest attempt. Logs are stored apart, see `logs`.
+pub(super) async fn record_attempt(
+    pool: &SqlitePool,
+    mutation: &Mutation,
+    result: &MutationResult,
+    owner: &str,
+    worker_id: Option<i64>,
+    max_log_size: usize,
+) -> Result<(), ServerError> {
+    let status = result.status.to_string();
+    let now = chrono::Utc::now().timestamp();
+    let mut tx = pool.begin().await?;
+
+    let result_id = sqlx::query!(
+        "INSERT INTO results (mutation_id, status, worker, worker_id, start_time, end_time, exit_code)
+        VALUES (?, ?, ?, (SELECT id FROM workers WHERE id = ? AND owner = ?), ?, ?, ?)",
+        mutation.id,
+        status,
+        owner,
+        worker_id,
+        owner,
+        mutation.start_time,
+        now,
+        result.exit_code
+    )
+    .execute(&mut tx)
+    .await?
+    .last_insert_rowid();
+
+    for (stream, log) in [
+        (Stream::Stdout, &result.stdout),
+        (Stream::Stderr, &result.stderr),
+    ] {
+        if let Some(log) = log {
+            logs::store_log(&mut tx, result_id, stream, log, max_log_size).await?;
+        }
+    }
+
+    sqlx::query!(
+        "UPDATE mutations SET status = (SELECT status FROM results WHERE mutation_id = ? ORDER BY id DESC LIMIT 1), end_time = ?, lease_expires_at = NULL WHERE id = ?",
+        mutation.id,
+        now,
+        mutation.id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Fills in the logs of the latest attempt. Mutations that ran before
+/// attempts were recorded still carry their own.
+pub(super) async fn attach_logs(
+    pool: &SqlitePool,
+    mutation: &mut Mutation,
+) -> Result<(), ServerError> {
+    if mutation.stdout.is_some() || mutation.stderr.is_some() {
+        return Ok(());
+    }
+
+    let latest = sqlx::query!(
+        "SELECT id FROM results WHERE mutation_id = ? AND requeued_at IS NULL ORDER BY id DESC LIMIT 1",
+        mutation.id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    if let Some(latest) = latest {
+        let load = |stream| logs::load_log(pool, latest.id, stream);
+        mutation.stdout = load(Stream::Stdout)
+            .await?
+            .map(|log| String::from_utf8_lossy(&log).into_owned());
+        mutation.stderr = load(Stream::Stderr)
+            .await?
+            .map(|log| String::from_utf8_lossy(&log).into_owned());
+    }
+
+    Ok(())
+}
+
+#[get("/mutation/{id}/results")]
+async fn list_attempts(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ServerError> {
+    authorize_read(&request, &ctx).await?;
+
+    let id = id.into_inner();
+    sqlx::query!("SELECT id FROM mutations WHERE id = ?", id)
+        .fetch_optional(&ctx.pool)
+        .await?
+        .ok_or_else(|| mutation_not_found(id))?;
+
+    let attempts = sqlx::query_as!(
+        Attempt,
+        "SELECT id, mutation_id, status, worker, worker_id, start_time, end_time, exit_code, requeued_at, requeued_by FROM results WHERE mutation_id = ? ORDER BY id",
+        id
+    )
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(attempts))
+}
+
+/// Mutations that were both killed and not killed across their attempts,
+/// usually a sign of a flaky test rather than of a weak one.
+#[get("/mutations/flaky")]
+async fn flaky_mutations(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+) -> Result<HttpResponse, ServerError> {
+    authorize_read(&request, &ctx).await?;
+
+    let filter: MutationFilter = parse_query(&request)?;
+
+    let mut query = QueryBuilder::new(
+        "SELECT id, file, line, status, campaign_id, fingerprint, runs, killed, not_killed FROM mutations
+        JOIN (SELECT mutation_id, COUNT(*) AS runs, SUM(status = ",
+    );
+    query
+        .push_bind(MutationStatus::Killed.to_string())
+        .push(") AS killed, SUM(status = ")
+        .push_bind(MutationStatus::NotKilled.to_string())
+        .push(
+            ") AS not_killed FROM results GROUP BY mutation_id) ON mutation_id = id WHERE killed > 0 AND not_killed > 0",
+        );
+    filter.push_conditions(&mut query);
+    query.push(" ORDER BY file, line");
+
+    let mutations: Vec<FlakyMutation> = query.build_query_as().fetch_all(&ctx.pool).await?;
+
+    Ok(HttpResponse::Ok().json(mutations))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    use crate::server::testing::{init_app, insert_pending, test_pool};
+
+    #[actix_web::test]
+    async fn test_attempts_are_kept_and_flaky_mutations_spotted() {
+        let (pool, _db) = test_pool().await;
+        insert_pending(&pool, 2).await;
+
+        let app = init_app(&pool).await;
+
+        // Mutation 1 is killed, then survives once requeued.
+        for (status, exit_code) in [
+            (MutationStatus::Killed, Some(2)),
+            (MutationStatus::NotKilled, Some(0)),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/get_work")
+                .insert_header(("Authorization", "Bearer bob-worker"))
+                .to_request();
+            let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(mutation.id, 1);
+
+            let req = test::TestRequest::post()
+                .uri("/mutations/1")
+                .insert_header(("Authorization", "Bearer bob-worker"))
+                .set_json(MutationResult {
+                    mutation_id: mutation.patch_md5.clone(),
+                    status: status.clone(),
+                    stdout: Some(format!("{} output", status)),
+                    stderr: None,
+                    exit_code,
+                })
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+            if status == MutationStatus::Killed {
+                let req = test::TestRequest::post()
+                    .uri("/mutation/1/requeue")
+                    .insert_header(("Authorization", "Bearer root-admin"))
+                    .to_request();
+                assert_eq!(test::call_service(&app, req).await.status(), 200);
+            }
+        }
+
+        let req = test::TestRequest::get().uri("/mutation/1").to_request();
+        let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(mutation.status, "NotKilled");
+        assert_eq!(mutation.stdout.as_deref(), Some("NotKilled output"));
+
+        let req = test::TestRequest::get()
+            .uri("/mutation/1/results")
+            .to_request();
+        let attempts: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(attempts.len(), 2);
+        assert_eq!(attempts[0]["status"], "Killed");
+        assert_eq!(attempts[0]["exit_code"], 2);
+        assert_eq!(attempts[0]["requeued_by"], "root");
+        assert_eq!(attempts[1]["worker"], "bob");
+        assert_eq!(attempts[1]["requeued_at"], serde_json::Value::Null);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/mutation/1/log?attempt={}", attempts[0]["id"]))
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "Killed output");
+
+        let req = test::TestRequest::get()
+            .uri("/mutations/flaky")
+            .to_request();
+        let flaky: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(flaky.len(), 1);
+        assert_eq!(flaky[0]["id"], 1);
+        assert_eq!(flaky[0]["runs"], 2);
+    }
+}
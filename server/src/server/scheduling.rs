@@ -0,0 +1,247 @@
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{QueryBuilder, Sqlite};
+
+use super::tokens::{authorize, Role};
+use super::{campaigns, capabilities};
+use super::{mutation_not_found, Context};
+use crate::errors::ServerError;
+
+/// How `get_work` picks the next pending mutation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Policy {
+    /// Highest campaign priority, then highest mutation priority, oldest first.
+    #[default]
+    Priority,
+    /// The campaign with the fewest running mutations, then the one handed
+    /// work the longest time ago, so a small campaign is not stuck behind a
+    /// large one.
+    RoundRobin,
+    /// Like `priority`, but keeps a worker on the file of its last result so
+    /// its ccache stays hot.
+    SameFile,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewPriority {
+    priority: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct MutationPriority {
+    id: i64,
+    priority: i64,
+}
+
+/// Appends a statement selecting the id of the next mutation to hand out
+/// among those with the `pending` status.
+pub(super) fn push_next_mutation(
+    query: &mut QueryBuilder<Sqlite>,
+    policy: Policy,
+    pending: &str,
+    running: &str,
+    owner: &str,
+    worker_id: Option<i64>,
+) {
+    query.push("SELECT m.id FROM mutations m LEFT JOIN campaigns c ON c.id = m.campaign_id");
+    if policy == Policy::RoundRobin {
+        query
+            .push(" LEFT JOIN (SELECT campaign_id, SUM(status = ")
+            .push_bind(running.to_string())
+            .push(
+                ") AS running, MAX(start_time) AS served_at FROM mutations GROUP BY campaign_id) s ON s.campaign_id = m.campaign_id",
+            );
+    }
+    query
+        .push(" WHERE m.status = ")
+        .push_bind(pending.to_string());
+    capabilities::push_requirements_met(query, "m", owner, worker_id);
+
+    match policy {
+        Policy::Priority => {
+            query.push(" ORDER BY COALESCE(c.priority, 0) DESC, m.priority DESC, m.id");
+        }
+        // Campaigns never served have no start time and come first.
+        Policy::RoundRobin => {
+            query.push(" ORDER BY s.running, s.served_at, m.campaign_id, m.priority DESC, m.id");
+        }
+        Policy::SameFile => {
+            query.push(
+                " ORDER BY COALESCE(c.priority, 0) DESC, m.priority DESC, m.file = (SELECT l.file FROM results r JOIN mutations l ON l.id = r.mutation_id WHERE ",
+            );
+            match worker_id {
+                Some(worker_id) => query.push("r.worker_id = ").push_bind(worker_id),
+                // Workers started before the registry are told apart by owner only.
+                None => query.push("r.worker = ").push_bind(owner.to_string()),
+            };
+            query.push(" ORDER BY r.id DESC LIMIT 1)");
+            query.push(" DESC, m.file, m.line, m.id");
+        }
+    }
+    query.push(" LIMIT 1");
+}
+
+#[post("/campaign/{id}/priority")]
+async fn set_campaign_priority(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+    priority: web::Json<NewPriority>,
+) -> Result<HttpResponse, ServerError> {
+    let admin = authorize(&request, &ctx, Role::Admin).await?;
+
+    let id = id.into_inner();
+    let r = sqlx::query!(
+        "UPDATE campaigns SET priority = ? WHERE id = ?",
+        priority.priority,
+        id
+    )
+    .execute(&ctx.pool)
+    .await?;
+    if r.rows_affected() == 0 {
+        return Err(ServerError::NotFound(format!("Campaign {} not found", id)));
+    }
+    println!(
+        "Priority of campaign {} set to {} by {}",
+        id, priority.priority, admin
+    );
+
+    let campaign = campaigns::fetch_campaign(&ctx.pool, id).await?;
+    Ok(HttpResponse::Ok().json(campaign))
+}
+
+#[post("/mutation/{id}/priority")]
+async fn set_mutation_priority(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+    priority: web::Json<NewPriority>,
+) -> Result<HttpResponse, ServerError> {
+    let admin = authorize(&request, &ctx, Role::Admin).await?;
+
+    let id = id.into_inner();
+    let r = sqlx::query!(
+        "UPDATE mutations SET priority = ? WHERE id = ?",
+        priority.priority,
+        id
+    )
+    .execute(&ctx.pool)
+    .await?;
+    if r.rows_affected() == 0 {
+        return Err(mutation_not_found(id));
+    }
+    println!(
+        "Priority of mutation {} set to {} by {}",
+        id, priority.priority, admin
+    );
+
+    Ok(HttpResponse::Ok().json(MutationPriority {
+        id,
+        priority: priority.priority,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+    use common::{Mutation, MutationResult, MutationStatus};
+
+    use crate::server::testing::{
+        init_app, init_app_with, insert_pending, test_context, test_pool,
+    };
+
+    #[actix_web::test]
+    async fn test_scheduling_policies() {
+        for (policy, submit, expected) in [
+            (Policy::Priority, false, [5, 4, 1, 2, 3]),
+            (Policy::RoundRobin, false, [1, 5, 2, 4, 3]),
+            (Policy::SameFile, true, [5, 4, 1, 3, 2]),
+        ] {
+            let (pool, _db) = test_pool().await;
+            for (name, priority) in [("nightly", 0), ("pr-1", 5)] {
+                sqlx::query(
+                    "INSERT INTO campaigns (name, source, creator, created_at, state, priority) VALUES (?, 'files', 'carol', 0, 'Open', ?)",
+                )
+                .bind(name)
+                .bind(priority)
+                .execute(&pool)
+                .await
+                .unwrap();
+            }
+            for (file, campaign, priority) in [
+                ("src/a.cpp", "nightly", 0),
+                ("src/b.cpp", "nightly", 0),
+                ("src/a.cpp", "nightly", 0),
+                ("src/c.cpp", "pr-1", 0),
+                ("src/c.cpp", "pr-1", 1),
+            ] {
+                sqlx::query(
+                    "INSERT INTO mutations (patch_md5, file, line, patch, status, campaign_id, priority) VALUES (hex(randomblob(8)), ?, 1, '', ?, (SELECT id FROM campaigns WHERE name = ?), ?)",
+                )
+                .bind(file)
+                .bind(MutationStatus::Pending.to_string())
+                .bind(campaign)
+                .bind(priority)
+                .execute(&pool)
+                .await
+                .unwrap();
+            }
+
+            let mut ctx = test_context(&pool).await;
+            ctx.scheduling = policy;
+            let app = init_app_with(ctx).await;
+
+            let mut claimed = vec![];
+            for _ in 0..expected.len() {
+                let req = test::TestRequest::post()
+                    .uri("/get_work")
+                    .insert_header(("Authorization", "Bearer bob-worker"))
+                    .to_request();
+                let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+                claimed.push(mutation.id);
+
+                if submit {
+                    let req = test::TestRequest::post()
+                        .uri(&format!("/mutations/{}", mutation.id))
+                        .insert_header(("Authorization", "Bearer bob-worker"))
+                        .set_json(MutationResult {
+                            mutation_id: mutation.patch_md5.clone(),
+                            status: MutationStatus::Killed,
+                            stdout: None,
+                            stderr: None,
+                            exit_code: Some(1),
+                        })
+                        .to_request();
+                    assert_eq!(test::call_service(&app, req).await.status(), 200);
+                }
+            }
+            assert_eq!(claimed, expected, "{:?}", policy);
+        }
+
+        let (pool, _db) = test_pool().await;
+        insert_pending(&pool, 1).await;
+        let app = init_app(&pool).await;
+        for (uri, token, status) in [
+            ("/mutation/1/priority", "carol-submitter", 403),
+            ("/mutation/1/priority", "root-admin", 200),
+            ("/mutation/2/priority", "root-admin", 404),
+            ("/campaign/1/priority", "root-admin", 200),
+        ] {
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(serde_json::json!({"priority": 10}))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), status, "{}", uri);
+        }
+
+        let req = test::TestRequest::get().uri("/mutation/1").to_request();
+        let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(mutation.priority, 10);
+        let req = test::TestRequest::get().uri("/campaign/1").to_request();
+        let campaign: common::Campaign = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(campaign.priority, 10);
+    }
+}
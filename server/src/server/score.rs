@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use common::MutationStatus;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqlitePool;
+
+use super::tokens::authorize_read;
+use super::Context;
+use crate::errors::ServerError;
+
+#[derive(Debug, Deserialize)]
+struct ScoreParams {
+    campaign: Option<i64>,
+    /// File or directory prefix, e.g. `src/wallet/`.
+    file: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Score {
+    counts: BTreeMap<String, i64>,
+    score: Option<f64>,
+}
+
+impl Score {
+    fn add(&mut self, status: &str, count: i64) {
+        *self.counts.entry(status.to_string()).or_default() += count;
+    }
+
+    /// Killed / (killed + survived). Ignored and equivalent mutants, and
+    /// those that did not run to completion, are left out.
+    fn compute(&mut self) {
+        let killed = self.count(MutationStatus::Killed);
+        let survived = self.count(MutationStatus::NotKilled);
+        if killed + survived > 0 {
+            self.score = Some(killed as f64 / (killed + survived) as f64);
+        }
+    }
+
+    fn count(&self, status: MutationStatus) -> i64 {
+        self.counts.get(&status.to_string()).copied().unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ScoreReport {
+    #[serde(flatten)]
+    total: Score,
+    mutators: BTreeMap<String, Score>,
+}
+
+#[get("/score")]
+async fn get_score(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+) -> Result<HttpResponse, ServerError> {
+    authorize_read(&request, &ctx).await?;
+
+    let params: ScoreParams = super::parse_query(&request)?;
+
+    let rows = sqlx::query!(
+        r#"SELECT COALESCE(mutator, 'unknown') AS "mutator!: String", status, COUNT(*) AS "count!: i64" FROM mutations
+        WHERE (? IS NULL OR campaign_id = ?) AND (? IS NULL OR substr(file, 1, length(?)) = ?)
+        GROUP BY mutator, status"#,
+        params.campaign,
+        params.campaign,
+        params.file,
+        params.file,
+        params.file
+    )
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    let mut report = ScoreReport {
+        total: Score::default(),
+        mutators: BTreeMap::new(),
+    };
+    for row in rows {
+        report.total.add(&row.status, row.count);
+        report
+            .mutators
+            .entry(row.mutator)
+            .or_default()
+            .add(&row.status, row.count);
+    }
+
+    report.total.compute();
+    for score in report.mutators.values_mut() {
+        score.compute();
+    }
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// The score of a single campaign, `None` until a mutation was killed or
+/// survived.
+pub(super) async fn campaign_score(
+    pool: &SqlitePool,
+    campaign_id: i64,
+) -> Result<Option<f64>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"SELECT status, COUNT(*) AS "count!: i64" FROM mutations WHERE campaign_id = ? GROUP BY status"#,
+        campaign_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut score = Score::default();
+    for row in rows {
+        score.add(&row.status, row.count);
+    }
+    score.compute();
+
+    Ok(score.score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    use crate::server::testing::{init_app, test_pool};
+
+    #[actix_web::test]
+    async fn test_score_by_directory_and_mutator() {
+        let (pool, _db) = test_pool().await;
+        for (file, mutator, status) in [
+            ("src/wallet/spend.cpp", "A", MutationStatus::Killed),
+            ("src/wallet/spend.cpp", "A", MutationStatus::Killed),
+            ("src/wallet/coins.cpp", "A", MutationStatus::NotKilled),
+            ("src/wallet/coins.cpp", "B", MutationStatus::NotKilled),
+            ("src/wallet/coins.cpp", "B", MutationStatus::Ignored),
+            ("src/validation.cpp", "B", MutationStatus::Killed),
+        ] {
+            sqlx::query(
+                "INSERT INTO mutations (patch_md5, file, line, patch, status, mutator) VALUES ('', ?, 1, '', ?, ?)",
+            )
+            .bind(file)
+            .bind(status.to_string())
+            .bind(mutator)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let app = init_app(&pool).await;
+
+        let req = test::TestRequest::get()
+            .uri("/score?file=src/wallet/")
+            .to_request();
+        let score: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(score["counts"]["Killed"], 2);
+        assert_eq!(score["counts"]["Ignored"], 1);
+        assert_eq!(score["score"], 0.5);
+        assert_eq!(score["mutators"]["A"]["score"], 2.0 / 3.0);
+        assert_eq!(score["mutators"]["B"]["score"], 0.0);
+
+        let req = test::TestRequest::get()
+            .uri("/score?campaign=x")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+    }
+}
@@ -0,0 +1,422 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use common::MutationStatus;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqlitePool;
+
+use super::tokens::{authorize, authorize_read, Role};
+use super::{mutation_not_found, Context};
+use crate::errors::ServerError;
+
+/// Where the follow-up of a surviving mutant stands. Survivors start open and
+/// are closed by the server once a later campaign kills them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(super) enum TaskState {
+    Open,
+    TestWritten,
+    WontFix,
+    Closed,
+}
+
+impl std::fmt::Display for TaskState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TaskState::Open => write!(f, "open"),
+            TaskState::TestWritten => write!(f, "test-written"),
+            TaskState::WontFix => write!(f, "wont-fix"),
+            TaskState::Closed => write!(f, "closed"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TaskUpdate {
+    state: Option<TaskState>,
+    /// An empty string unassigns the mutation.
+    assignee: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Task {
+    id: i64,
+    task_state: Option<String>,
+    assignee: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewComment {
+    body: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Comment {
+    id: i64,
+    mutation_id: i64,
+    author: String,
+    body: String,
+    created_at: i64,
+}
+
+#[post("/mutation/{id}/task")]
+async fn update_task(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+    update: web::Json<TaskUpdate>,
+) -> Result<HttpResponse, ServerError> {
+    let reviewer = authorize(&request, &ctx, Role::Reviewer).await?;
+
+    let id = id.into_inner();
+    let update = update.into_inner();
+    if update.state.is_none() && update.assignee.is_none() {
+        return Err(ServerError::BadRequest(
+            "Nothing to update: give a state or an assignee".to_string(),
+        ));
+    }
+
+    let state = update.state.map(|state| state.to_string());
+    let r = sqlx::query!(
+        "UPDATE mutations SET task_state = COALESCE(?, task_state), assignee = CASE WHEN ? IS NULL THEN assignee ELSE NULLIF(?, '') END WHERE id = ?",
+        state,
+        update.assignee,
+        update.assignee,
+        id
+    )
+    .execute(&ctx.pool)
+    .await?;
+    if r.rows_affected() == 0 {
+        return Err(mutation_not_found(id));
+    }
+
+    let task = sqlx::query_as!(
+        Task,
+        "SELECT id, task_state, assignee FROM mutations WHERE id = ?",
+        id
+    )
+    .fetch_one(&ctx.pool)
+    .await?;
+    println!(
+        "Task for mutation {} updated by {}: {:?}, assigned to {:?}",
+        id, reviewer, task.task_state, task.assignee
+    );
+
+    Ok(HttpResponse::Ok().json(task))
+}
+
+#[get("/mutation/{id}/comments")]
+async fn list_comments(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ServerError> {
+    authorize_read(&request, &ctx).await?;
+
+    let id = id.into_inner();
+    ensure_mutation_exists(&ctx.pool, id).await?;
+
+    let comments = sqlx::query_as!(
+        Comment,
+        "SELECT * FROM comments WHERE mutation_id = ? ORDER BY id",
+        id
+    )
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(comments))
+}
+
+#[post("/mutation/{id}/comments")]
+async fn add_comment(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+    comment: web::Json<NewComment>,
+) -> Result<HttpResponse, ServerError> {
+    let author = authorize(&request, &ctx, Role::Reviewer).await?;
+
+    let id = id.into_inner();
+    let body = comment.body.trim();
+    if body.is_empty() {
+        return Err(ServerError::BadRequest(
+            "A comment needs a body".to_string(),
+        ));
+    }
+    ensure_mutation_exists(&ctx.pool, id).await?;
+
+    let now = chrono::Utc::now().timestamp();
+    let comment_id = sqlx::query!(
+        "INSERT INTO comments (mutation_id, author, body, created_at) VALUES (?, ?, ?, ?)",
+        id,
+        author,
+        body,
+        now
+    )
+    .execute(&ctx.pool)
+    .await?
+    .last_insert_rowid();
+
+    let comment = sqlx::query_as!(Comment, "SELECT * FROM comments WHERE id = ?", comment_id)
+        .fetch_one(&ctx.pool)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(comment))
+}
+
+async fn ensure_mutation_exists(pool: &SqlitePool, id: i64) -> Result<(), ServerError> {
+    sqlx::query!("SELECT id FROM mutations WHERE id = ?", id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| mutation_not_found(id))?;
+
+    Ok(())
+}
+
+/// Opens a task for a new survivor, and closes the open tasks of the same
+/// mutant once it is killed, whether it ran again or in another campaign.
+pub(super) async fn record_result(
+    pool: &SqlitePool,
+    id: i64,
+    fingerprint: Option<&str>,
+    status: &MutationStatus,
+) {
+    let r = match (status, fingerprint) {
+        (MutationStatus::NotKilled, _) => {
+            sqlx::query("UPDATE mutations SET task_state = ? WHERE id = ? AND task_state IS NULL")
+                .bind(TaskState::Open.to_string())
+                .bind(id)
+                .execute(pool)
+                .await
+        }
+        (MutationStatus::Killed, _) => close_survivors(pool, id, fingerprint).await,
+        _ => return,
+    };
+
+    if let Err(e) = r {
+        println!("Error updating tasks for mutation {}: {}", id, e);
+    }
+}
+
+async fn close_survivors(
+    pool: &SqlitePool,
+    killed_by: i64,
+    fingerprint: Option<&str>,
+) -> Result<sqlx::sqlite::SqliteQueryResult, sqlx::Error> {
+    // The killed mutation's own task was opened when an earlier attempt
+    // survived.
+    let survivors = "(id = ? OR (fingerprint = ? AND status = ?)) AND task_state IN (?, ?)";
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(&format!(
+        "INSERT INTO comments (mutation_id, author, body, created_at) SELECT id, 'server', ?, ? FROM mutations WHERE {}",
+        survivors
+    ))
+    .bind(format!("Closed: killed by mutation {}", killed_by))
+    .bind(chrono::Utc::now().timestamp())
+    .bind(killed_by)
+    .bind(fingerprint)
+    .bind(MutationStatus::NotKilled.to_string())
+    .bind(TaskState::Open.to_string())
+    .bind(TaskState::TestWritten.to_string())
+    .execute(&mut tx)
+    .await?;
+
+    let r = sqlx::query(&format!(
+        "UPDATE mutations SET task_state = ? WHERE {}",
+        survivors
+    ))
+    .bind(TaskState::Closed.to_string())
+    .bind(killed_by)
+    .bind(fingerprint)
+    .bind(MutationStatus::NotKilled.to_string())
+    .bind(TaskState::Open.to_string())
+    .bind(TaskState::TestWritten.to_string())
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    if r.rows_affected() > 0 {
+        println!(
+            "Closed {} survivors killed by mutation {}",
+            r.rows_affected(),
+            killed_by
+        );
+    }
+
+    Ok(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+    use common::{Mutation, MutationResult};
+
+    use crate::server::testing::{init_app, test_pool};
+
+    #[actix_web::test]
+    async fn test_survivor_tasks_are_tracked_and_closed_when_killed() {
+        let (pool, _db) = test_pool().await;
+        let app = init_app(&pool).await;
+
+        let req = test::TestRequest::post()
+            .uri("/campaigns")
+            .insert_header(("Authorization", "Bearer carol-submitter"))
+            .set_json(common::NewCampaign {
+                name: "nightly".to_string(),
+                base_commit: None,
+                source: "files".to_string(),
+                source_ref: None,
+                priority: None,
+                requires: None,
+            })
+            .to_request();
+        let nightly: common::Campaign = test::call_and_read_body_json(&app, req).await;
+
+        // The mutant survives in the default campaign, then is killed in the
+        // nightly one.
+        for (campaign, status) in [
+            (None, MutationStatus::NotKilled),
+            (Some(nightly.id), MutationStatus::Killed),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/mutations")
+                .insert_header(("Authorization", "Bearer carol-submitter"))
+                .set_json(serde_json::json!([{
+                    "id": 0,
+                    "patch_md5": "md5",
+                    "file": "src/validation.cpp",
+                    "line": 1,
+                    "patch": "",
+                    "status": "Pending",
+                    "fingerprint": "fp",
+                    "campaign_id": campaign,
+                }]))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+            let req = test::TestRequest::post()
+                .uri("/get_work")
+                .insert_header(("Authorization", "Bearer bob-worker"))
+                .to_request();
+            let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+            let req = test::TestRequest::post()
+                .uri(&format!("/mutations/{}", mutation.id))
+                .insert_header(("Authorization", "Bearer bob-worker"))
+                .set_json(MutationResult {
+                    mutation_id: mutation.patch_md5.clone(),
+                    status,
+                    stdout: None,
+                    stderr: None,
+                    exit_code: None,
+                })
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+            if campaign.is_some() {
+                break;
+            }
+
+            let req = test::TestRequest::get().uri("/mutation/1").to_request();
+            let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(mutation.task_state.as_deref(), Some("open"));
+
+            let req = test::TestRequest::post()
+                .uri("/mutation/1/task")
+                .insert_header(("Authorization", "Bearer dave-reviewer"))
+                .set_json(serde_json::json!({"state": "test-written", "assignee": "erin"}))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 200);
+            let req = test::TestRequest::post()
+                .uri("/mutation/1/comments")
+                .insert_header(("Authorization", "Bearer dave-reviewer"))
+                .set_json(serde_json::json!({"body": "see feature_block.py"}))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+            for (query, expected) in [
+                ("assignee=erin&task_state=test-written", 1),
+                ("task_state=open", 0),
+            ] {
+                let req = test::TestRequest::get()
+                    .uri(&format!("/mutations/NotKilled/count?{}", query))
+                    .to_request();
+                let count: i64 = test::call_and_read_body_json(&app, req).await;
+                assert_eq!(count, expected, "{}", query);
+            }
+            let req = test::TestRequest::get()
+                .uri("/mutations/NotKilled?task_state=done")
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 400);
+        }
+
+        let req = test::TestRequest::get().uri("/mutation/1").to_request();
+        let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(mutation.task_state.as_deref(), Some("closed"));
+        assert_eq!(mutation.assignee.as_deref(), Some("erin"));
+
+        let req = test::TestRequest::get()
+            .uri("/mutation/1/comments")
+            .to_request();
+        let comments: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        let authors: Vec<&str> = comments
+            .iter()
+            .map(|c| c["author"].as_str().unwrap())
+            .collect();
+        assert_eq!(authors, ["dave", "server"]);
+    }
+
+    #[actix_web::test]
+    async fn test_survivor_tasks_are_closed_when_killed_again_in_their_campaign() {
+        let (pool, _db) = test_pool().await;
+        let app = init_app(&pool).await;
+
+        let req = test::TestRequest::post()
+            .uri("/mutations")
+            .insert_header(("Authorization", "Bearer carol-submitter"))
+            .set_json(serde_json::json!([{
+                "id": 0,
+                "patch_md5": "md5",
+                "file": "src/validation.cpp",
+                "line": 1,
+                "patch": "",
+                "status": "Pending",
+                "fingerprint": "fp",
+            }]))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+        // It survives, then is killed once requeued after a test was added.
+        for (status, task_state) in [
+            (MutationStatus::NotKilled, "open"),
+            (MutationStatus::Killed, "closed"),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/get_work")
+                .insert_header(("Authorization", "Bearer bob-worker"))
+                .to_request();
+            let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+            let req = test::TestRequest::post()
+                .uri(&format!("/mutations/{}", mutation.id))
+                .insert_header(("Authorization", "Bearer bob-worker"))
+                .set_json(MutationResult {
+                    mutation_id: mutation.patch_md5.clone(),
+                    status,
+                    stdout: None,
+                    stderr: None,
+                    exit_code: None,
+                })
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+            let req = test::TestRequest::get().uri("/mutation/1").to_request();
+            let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(mutation.task_state.as_deref(), Some(task_state));
+
+            let req = test::TestRequest::post()
+                .uri("/mutation/1/requeue")
+                .insert_header(("Authorization", "Bearer root-admin"))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 200);
+        }
+    }
+}
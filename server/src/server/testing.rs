@@ -0,0 +1,88 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use actix_http::Request;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::{test, web, App};
+use common::MutationStatus;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+use tempfile::TempDir;
+
+use super::tokens::{self, Role};
+use super::{live, migrations, routes, Context, Policy};
+
+/// A database in a directory removed when the returned `TempDir` is dropped,
+/// so it has to be kept for as long as the pool is used.
+pub(super) async fn empty_pool() -> (SqlitePool, TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bcm.db");
+
+    let options = SqliteConnectOptions::from_str(&format!("sqlite://{}", path.display()))
+        .unwrap()
+        .create_if_missing(true);
+    let pool = SqlitePool::connect_with(options).await.unwrap();
+
+    (pool, dir)
+}
+
+/// Like `empty_pool`, with the schema of the server.
+pub(super) async fn test_pool() -> (SqlitePool, TempDir) {
+    let (pool, dir) = empty_pool().await;
+    migrations::migrate(&pool).await.unwrap();
+
+    (pool, dir)
+}
+
+pub(super) async fn insert_pending(pool: &SqlitePool, count: usize) {
+    for i in 0..count {
+        sqlx::query(
+            "INSERT INTO mutations (patch_md5, file, line, patch, status) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(format!("md5-{}", i))
+        .bind("src/validation.cpp")
+        .bind(i as i64)
+        .bind("")
+        .bind(MutationStatus::Pending.to_string())
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+}
+
+/// A context whose tokens are `secret` (alice, submitter and worker),
+/// `bob-worker`, `carol-submitter`, `dave-reviewer` and `root-admin`.
+pub(super) async fn test_context(pool: &SqlitePool) -> Context {
+    for (owner, token, roles) in [
+        ("alice", "secret", vec![Role::Submitter, Role::Worker]),
+        ("bob", "bob-worker", vec![Role::Worker]),
+        ("carol", "carol-submitter", vec![Role::Submitter]),
+        ("dave", "dave-reviewer", vec![Role::Reviewer]),
+        ("root", "root-admin", vec![Role::Admin]),
+    ] {
+        tokens::seed_token(pool, owner, token, &roles)
+            .await
+            .unwrap();
+    }
+
+    Context {
+        pool: pool.clone(),
+        lease_duration: 600,
+        protect_reads: false,
+        max_log_size: 1024 * 1024,
+        scheduling: Policy::Priority,
+        live: Arc::new(live::Live::new()),
+    }
+}
+
+pub(super) async fn init_app(
+    pool: &SqlitePool,
+) -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error> {
+    init_app_with(test_context(pool).await).await
+}
+
+/// Serves the routes with a context changed from `test_context`.
+pub(super) async fn init_app_with(
+    ctx: Context,
+) -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error> {
+    test::init_service(App::new().app_data(web::Data::new(ctx)).configure(routes)).await
+}
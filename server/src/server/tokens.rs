@@ -0,0 +1,413 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::SqlitePool;
+
+use super::Context;
+use crate::errors::ServerError;
+
+/// What a token may be used for. Admins may do everything.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum Role {
+    Submitter,
+    Worker,
+    Reviewer,
+    Admin,
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "submitter" => Ok(Role::Submitter),
+            "worker" => Ok(Role::Worker),
+            "reviewer" => Ok(Role::Reviewer),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("Unknown role: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Role::Submitter => write!(f, "submitter"),
+            Role::Worker => write!(f, "worker"),
+            Role::Reviewer => write!(f, "reviewer"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+fn parse_roles(roles: &str) -> Vec<Role> {
+    roles.split(',').filter_map(|r| r.parse().ok()).collect()
+}
+
+fn format_roles(roles: &[Role]) -> String {
+    roles
+        .iter()
+        .map(|r| r.to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct Token {
+    pub(super) owner: String,
+    pub(super) roles: Vec<Role>,
+}
+
+impl Token {
+    fn has_role(&self, role: Role) -> bool {
+        self.roles.contains(&role) || self.roles.contains(&Role::Admin)
+    }
+}
+
+/// Tokens are only stored as their SHA-256, so a leaked database does not
+/// leak working credentials.
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Looks up an active token. Every active token is compared in constant
+/// time so response times do not reveal how close a guess was.
+async fn find_token(pool: &SqlitePool, token: &str) -> Result<Option<Token>, sqlx::Error> {
+    let hash = hash_token(token);
+    let now = chrono::Utc::now().timestamp();
+    let rows = sqlx::query!(
+        "SELECT owner, token_hash, roles FROM tokens WHERE revoked_at IS NULL AND (expires_at IS NULL OR expires_at > ?)",
+        now
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut found = None;
+    for row in rows {
+        if constant_time_eq::constant_time_eq(hash.as_bytes(), row.token_hash.as_bytes()) {
+            found = Some(Token {
+                owner: row.owner,
+                roles: parse_roles(&row.roles),
+            });
+        }
+    }
+
+    Ok(found)
+}
+
+/// Adds a token given on the command line, or updates its owner and roles if
+/// it is already known. Revocations and expiries set at runtime are kept.
+pub(super) async fn seed_token(
+    pool: &SqlitePool,
+    owner: &str,
+    token: &str,
+    roles: &[Role],
+) -> Result<(), sqlx::Error> {
+    let hash = hash_token(token);
+    let roles = format_roles(roles);
+    let now = chrono::Utc::now().timestamp();
+    sqlx::query!(
+        "INSERT INTO tokens (owner, token_hash, roles, created_at) VALUES (?, ?, ?, ?)
+        ON CONFLICT (token_hash) DO UPDATE SET owner = excluded.owner, roles = excluded.roles",
+        owner,
+        hash,
+        roles,
+        now
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Returns the owner of the token sent in the Authorization header if it
+/// grants `role`.
+pub(super) async fn authorize(
+    request: &HttpRequest,
+    ctx: &Context,
+    role: Role,
+) -> Result<String, ServerError> {
+    let token = authenticate(request, ctx).await?;
+    if !token.has_role(role) {
+        return Err(ServerError::Forbidden(format!(
+            "Token is not allowed to act as {}",
+            role
+        )));
+    }
+
+    Ok(token.owner)
+}
+
+/// Accepts both `Authorization: Bearer <token>` and the bare token sent by
+/// older clients.
+async fn authenticate(request: &HttpRequest, ctx: &Context) -> Result<Token, ServerError> {
+    let auth_header = request
+        .headers()
+        .get("Authorization")
+        .ok_or_else(|| ServerError::Unauthorized("Missing Authorization header".to_string()))?
+        .to_str()
+        .unwrap_or_default();
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or(auth_header);
+
+    find_token(&ctx.pool, token)
+        .await?
+        .ok_or_else(|| ServerError::Unauthorized("Invalid token".to_string()))
+}
+
+/// Read endpoints are public unless the server runs with `--protect-reads`,
+/// in which case any valid token may read.
+pub(super) async fn authorize_read(
+    request: &HttpRequest,
+    ctx: &Context,
+) -> Result<(), ServerError> {
+    if ctx.protect_reads {
+        authenticate(request, ctx).await?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct NewToken {
+    owner: String,
+    roles: Vec<Role>,
+    expires_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CreatedToken {
+    id: i64,
+    owner: String,
+    roles: Vec<Role>,
+    expires_at: Option<i64>,
+    /// Only ever returned here: the server keeps the hash.
+    token: String,
+}
+
+#[derive(Debug, Serialize)]
+struct TokenInfo {
+    id: i64,
+    owner: String,
+    roles: Vec<Role>,
+    created_at: i64,
+    expires_at: Option<i64>,
+    revoked_at: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenExpiry {
+    expires_at: Option<i64>,
+}
+
+#[post("/tokens")]
+async fn create_token(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    new_token: web::Json<NewToken>,
+) -> Result<HttpResponse, ServerError> {
+    authorize(&request, &ctx, Role::Admin).await?;
+
+    let new_token = new_token.into_inner();
+    let token = format!("bcm_{}", hex::encode(rand::thread_rng().gen::<[u8; 32]>()));
+    let hash = hash_token(&token);
+    let roles = format_roles(&new_token.roles);
+    let now = chrono::Utc::now().timestamp();
+
+    let r = sqlx::query!(
+        "INSERT INTO tokens (owner, token_hash, roles, created_at, expires_at) VALUES (?, ?, ?, ?, ?)",
+        new_token.owner,
+        hash,
+        roles,
+        now,
+        new_token.expires_at
+    )
+    .execute(&ctx.pool)
+    .await?;
+
+    let id = r.last_insert_rowid();
+    println!("Created token {} for {}", id, new_token.owner);
+    Ok(HttpResponse::Ok().json(CreatedToken {
+        id,
+        owner: new_token.owner,
+        roles: new_token.roles,
+        expires_at: new_token.expires_at,
+        token,
+    }))
+}
+
+#[get("/tokens")]
+async fn list_tokens(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+) -> Result<HttpResponse, ServerError> {
+    authorize(&request, &ctx, Role::Admin).await?;
+
+    let rows = sqlx::query!(
+        "SELECT id, owner, roles, created_at, expires_at, revoked_at FROM tokens ORDER BY id"
+    )
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    let tokens: Vec<TokenInfo> = rows
+        .into_iter()
+        .map(|row| TokenInfo {
+            id: row.id,
+            owner: row.owner,
+            roles: parse_roles(&row.roles),
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+            revoked_at: row.revoked_at,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+#[post("/tokens/{id}/revoke")]
+async fn revoke_token(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ServerError> {
+    authorize(&request, &ctx, Role::Admin).await?;
+
+    let id = id.into_inner();
+    let r = sqlx::query("UPDATE tokens SET revoked_at = ? WHERE id = ? AND revoked_at IS NULL")
+        .bind(chrono::Utc::now().timestamp())
+        .bind(id)
+        .execute(&ctx.pool)
+        .await?;
+
+    if r.rows_affected() == 0 {
+        return Err(ServerError::NotFound(format!("Token {} not found", id)));
+    }
+
+    Ok(HttpResponse::Ok().body("Token revoked"))
+}
+
+/// Sets when a token stops being accepted, immediately if no time is given.
+#[post("/tokens/{id}/expire")]
+async fn expire_token(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+    expiry: web::Json<TokenExpiry>,
+) -> Result<HttpResponse, ServerError> {
+    authorize(&request, &ctx, Role::Admin).await?;
+
+    let expires_at = expiry
+        .expires_at
+        .unwrap_or_else(|| chrono::Utc::now().timestamp());
+    let id = id.into_inner();
+    let r = sqlx::query("UPDATE tokens SET expires_at = ? WHERE id = ?")
+        .bind(expires_at)
+        .bind(id)
+        .execute(&ctx.pool)
+        .await?;
+
+    if r.rows_affected() == 0 {
+        return Err(ServerError::NotFound(format!("Token {} not found", id)));
+    }
+
+    Ok(HttpResponse::Ok().json(expires_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test;
+    use common::{Mutation, MutationResult, MutationStatus};
+
+    use crate::server::testing::{init_app, insert_pending, test_pool};
+
+    #[actix_web::test]
+    async fn test_tokens_are_limited_to_their_roles() {
+        let (pool, _db) = test_pool().await;
+        insert_pending(&pool, 1).await;
+
+        let app = init_app(&pool).await;
+
+        let req = test::TestRequest::post()
+            .uri("/get_work")
+            .insert_header(("Authorization", "carol-submitter"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+
+        let req = test::TestRequest::post()
+            .uri("/get_work")
+            .insert_header(("Authorization", "secret"))
+            .to_request();
+        let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+
+        // Only the worker holding the mutation may report on it.
+        for (token, expected) in [("bob-worker", 409), ("secret", 200), ("secret", 409)] {
+            let req = test::TestRequest::post()
+                .uri(&format!("/mutations/{}", mutation.id))
+                .insert_header(("Authorization", token))
+                .set_json(MutationResult {
+                    mutation_id: mutation.patch_md5.clone(),
+                    status: MutationStatus::NotKilled,
+                    stdout: None,
+                    stderr: None,
+                    exit_code: None,
+                })
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), expected);
+        }
+
+        for (token, expected) in [("secret", 403), ("root-admin", 200)] {
+            let req = test::TestRequest::post()
+                .uri(&format!("/mutation/{}/requeue", mutation.id))
+                .insert_header(("Authorization", token))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), expected);
+        }
+
+        let status: String = sqlx::query_scalar("SELECT status FROM mutations WHERE id = ?")
+            .bind(mutation.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(status, MutationStatus::Pending.to_string());
+    }
+
+    #[actix_web::test]
+    async fn test_tokens_can_be_created_and_revoked_at_runtime() {
+        let (pool, _db) = test_pool().await;
+        let app = init_app(&pool).await;
+
+        let req = test::TestRequest::post()
+            .uri("/tokens")
+            .insert_header(("Authorization", "Bearer root-admin"))
+            .set_json(serde_json::json!({"owner": "dave", "roles": ["worker"]}))
+            .to_request();
+        let created: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let token = format!("Bearer {}", created["token"].as_str().unwrap());
+
+        let req = test::TestRequest::post()
+            .uri("/get_work")
+            .insert_header(("Authorization", token.as_str()))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 204);
+
+        // Only hashes are stored.
+        let stored: Vec<String> = sqlx::query_scalar("SELECT token_hash FROM tokens")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert!(!stored.iter().any(|t| token.ends_with(t.as_str())));
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/tokens/{}/revoke", created["id"]))
+            .insert_header(("Authorization", "Bearer root-admin"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+        let req = test::TestRequest::post()
+            .uri("/get_work")
+            .insert_header(("Authorization", token.as_str()))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 401);
+    }
+}
@@ -0,0 +1,285 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use common::MutationStatus;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqlitePool;
+
+use super::tokens::{authorize, authorize_read, Role};
+use super::{campaigns, mutation_not_found, webhooks, Context};
+use crate::errors::ServerError;
+
+#[derive(Debug, Deserialize)]
+struct TriageDecision {
+    status: MutationStatus,
+    reason: String,
+}
+
+#[derive(Debug, Serialize)]
+struct TriageEntry {
+    id: i64,
+    mutation_id: i64,
+    fingerprint: Option<String>,
+    previous_status: String,
+    status: String,
+    reason: String,
+    reviewer: String,
+    created_at: i64,
+    carried_from: Option<i64>,
+}
+
+/// Records a review of a mutation, usually a survivor, as `Ignored` or
+/// `Equivalent`. The result it had is kept in the triage history.
+#[post("/mutation/{id}/triage")]
+async fn triage_mutation(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+    decision: web::Json<TriageDecision>,
+) -> Result<HttpResponse, ServerError> {
+    let reviewer = authorize(&request, &ctx, Role::Reviewer).await?;
+
+    let id = id.into_inner();
+    let decision = decision.into_inner();
+    if !matches!(
+        decision.status,
+        MutationStatus::Ignored | MutationStatus::Equivalent
+    ) {
+        return Err(ServerError::BadRequest(
+            "Mutations can only be triaged as Ignored or Equivalent".to_string(),
+        ));
+    }
+    let reason = decision.reason.trim();
+    if reason.is_empty() {
+        return Err(ServerError::BadRequest("A reason is required".to_string()));
+    }
+
+    let mutation = sqlx::query!(
+        "SELECT status, fingerprint, campaign_id FROM mutations WHERE id = ?",
+        id
+    )
+    .fetch_optional(&ctx.pool)
+    .await?
+    .ok_or_else(|| mutation_not_found(id))?;
+    if mutation.status == MutationStatus::Running.to_string() {
+        return Err(ServerError::Conflict(format!("Mutation {} is running", id)));
+    }
+
+    let status = decision.status.to_string();
+    let now = chrono::Utc::now().timestamp();
+    let mut tx = ctx.pool.begin().await?;
+    sqlx::query!("UPDATE mutations SET status = ? WHERE id = ?", status, id)
+        .execute(&mut tx)
+        .await?;
+    let entry_id = sqlx::query!(
+        "INSERT INTO triage (mutation_id, fingerprint, previous_status, status, reason, reviewer, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+        id,
+        mutation.fingerprint,
+        mutation.status,
+        status,
+        reason,
+        reviewer,
+        now
+    )
+    .execute(&mut tx)
+    .await?
+    .last_insert_rowid();
+    tx.commit().await?;
+
+    campaigns::refresh_states(&ctx.pool).await;
+    println!("Mutation {} triaged as {} by {}", id, status, reviewer);
+    ctx.live.publish_status(id, &status, None);
+    if let Some(campaign_id) = mutation.campaign_id {
+        webhooks::check_score(&ctx.pool, campaign_id).await;
+    }
+
+    let entry = sqlx::query_as!(TriageEntry, "SELECT * FROM triage WHERE id = ?", entry_id)
+        .fetch_one(&ctx.pool)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(entry))
+}
+
+#[get("/mutation/{id}/triage")]
+async fn triage_history(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ServerError> {
+    authorize_read(&request, &ctx).await?;
+
+    let id = id.into_inner();
+    sqlx::query!("SELECT id FROM mutations WHERE id = ?", id)
+        .fetch_optional(&ctx.pool)
+        .await?
+        .ok_or_else(|| mutation_not_found(id))?;
+
+    let history = sqlx::query_as!(
+        TriageEntry,
+        "SELECT * FROM triage WHERE mutation_id = ? ORDER BY id",
+        id
+    )
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(history))
+}
+
+/// Applies the latest decision taken on a mutant with the same fingerprint,
+/// in any campaign, to a newly stored pending mutation. Returns the status it
+/// was given, if any.
+pub(super) async fn carry_over(
+    pool: &SqlitePool,
+    mutation_id: i64,
+    fingerprint: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    let decision = sqlx::query!(
+        "SELECT id, status, reason, reviewer FROM triage WHERE fingerprint = ? AND mutation_id != ? ORDER BY id DESC LIMIT 1",
+        fingerprint,
+        mutation_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let decision = match decision {
+        Some(decision) => decision,
+        None => return Ok(None),
+    };
+
+    let pending = MutationStatus::Pending.to_string();
+    let now = chrono::Utc::now().timestamp();
+    let mut tx = pool.begin().await?;
+    let r = sqlx::query!(
+        "UPDATE mutations SET status = ? WHERE id = ? AND status = ?",
+        decision.status,
+        mutation_id,
+        pending
+    )
+    .execute(&mut tx)
+    .await?;
+    if r.rows_affected() == 0 {
+        return Ok(None);
+    }
+
+    sqlx::query!(
+        "INSERT INTO triage (mutation_id, fingerprint, previous_status, status, reason, reviewer, created_at, carried_from) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        mutation_id,
+        fingerprint,
+        pending,
+        decision.status,
+        decision.reason,
+        decision.reviewer,
+        now,
+        decision.id
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(Some(decision.status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+    use common::Mutation;
+
+    use crate::server::testing::{init_app, test_pool};
+
+    #[actix_web::test]
+    async fn test_triage_is_audited_and_carried_over() {
+        use actix_web::body::MessageBody;
+
+        let (pool, _db) = test_pool().await;
+        let app = init_app(&pool).await;
+
+        let upload = |campaign: Option<i64>| {
+            test::TestRequest::post()
+                .uri("/mutations")
+                .insert_header(("Authorization", "Bearer carol-submitter"))
+                .set_json(serde_json::json!([{
+                    "id": 0,
+                    "patch_md5": "md5",
+                    "file": "src/validation.cpp",
+                    "line": 1,
+                    "patch": "",
+                    "status": "Pending",
+                    "fingerprint": "fp",
+                    "campaign_id": campaign,
+                }]))
+                .to_request()
+        };
+        assert_eq!(test::call_service(&app, upload(None)).await.status(), 200);
+        sqlx::query("UPDATE mutations SET status = ?")
+            .bind(MutationStatus::NotKilled.to_string())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let triage = |token: &str, status: &str, reason: &str| {
+            test::TestRequest::post()
+                .uri("/mutation/1/triage")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(serde_json::json!({"status": status, "reason": reason}))
+                .to_request()
+        };
+        for (token, status, reason, expected) in [
+            ("bob-worker", "Equivalent", "same behaviour", 403),
+            ("dave-reviewer", "Killed", "same behaviour", 400),
+            ("dave-reviewer", "Equivalent", " ", 400),
+            ("dave-reviewer", "Equivalent", "same behaviour", 200),
+        ] {
+            let res = test::call_service(&app, triage(token, status, reason)).await;
+            assert_eq!(res.status(), expected, "{} {} {:?}", token, status, reason);
+        }
+
+        let req = test::TestRequest::get().uri("/mutation/1").to_request();
+        let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(mutation.status, "Equivalent");
+
+        let req = test::TestRequest::get()
+            .uri("/mutation/1/triage")
+            .to_request();
+        let history: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0]["previous_status"], "NotKilled");
+        assert_eq!(history[0]["reviewer"], "dave");
+
+        // The same mutant uploaded to another campaign inherits the decision.
+        let req = test::TestRequest::post()
+            .uri("/campaigns")
+            .insert_header(("Authorization", "Bearer carol-submitter"))
+            .set_json(common::NewCampaign {
+                name: "nightly".to_string(),
+                base_commit: None,
+                source: "files".to_string(),
+                source_ref: None,
+                priority: None,
+                requires: None,
+            })
+            .to_request();
+        let campaign: common::Campaign = test::call_and_read_body_json(&app, req).await;
+        let req = test::TestRequest::get().uri("/events").to_request();
+        let mut statuses = Box::pin(test::call_service(&app, req).await.into_body());
+        let res = test::call_service(&app, upload(Some(campaign.id))).await;
+        assert_eq!(res.status(), 200);
+
+        // Followers see the decision without reloading.
+        let next = std::future::poll_fn(|cx| statuses.as_mut().poll_next(cx));
+        let frame = tokio::time::timeout(std::time::Duration::from_secs(10), next)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let frame = String::from_utf8(frame.to_vec()).unwrap();
+        assert!(frame.contains(r#""mutation_id":2"#), "{}", frame);
+        assert!(frame.contains(r#""status":"Equivalent""#), "{}", frame);
+
+        let req = test::TestRequest::get()
+            .uri("/mutation/2/triage")
+            .to_request();
+        let history: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(history[0]["status"], "Equivalent");
+        assert_eq!(history[0]["previous_status"], "Pending");
+        assert_eq!(history[0]["carried_from"], 1);
+    }
+}
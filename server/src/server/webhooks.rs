@@ -0,0 +1,651 @@
+use std::time::Duration;
+
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse};
+use common::{Campaign, CampaignState, Mutation, MutationStatus};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::sqlite::SqlitePool;
+
+use super::tokens::{authorize, Role};
+use super::{campaigns, fetch_mutation, score, Context};
+use crate::errors::ServerError;
+
+/// Deliveries still waiting for an answer after this long are given up.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a webhook can be told about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum Event {
+    /// Every mutation of a campaign ran.
+    CampaignCompleted,
+    /// The score of a campaign went above or below the webhook's threshold.
+    ScoreThreshold,
+    /// A mutation in one of the webhook's files survived for the first time.
+    NewSurvivor,
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Event::CampaignCompleted => write!(f, "campaign_completed"),
+            Event::ScoreThreshold => write!(f, "score_threshold"),
+            Event::NewSurvivor => write!(f, "new_survivor"),
+        }
+    }
+}
+
+/// A row of `webhooks`, whose events and files are stored as JSON.
+struct WebhookRow {
+    id: i64,
+    url: String,
+    secret: String,
+    events: String,
+    campaign_id: Option<i64>,
+    threshold: Option<f64>,
+    files: String,
+    creator: String,
+    created_at: i64,
+}
+
+impl WebhookRow {
+    fn events(&self) -> Vec<Event> {
+        serde_json::from_str(&self.events).unwrap_or_default()
+    }
+
+    fn files(&self) -> Vec<String> {
+        serde_json::from_str(&self.files).unwrap_or_default()
+    }
+
+    /// Whether the webhook wants `event` for a mutation of `campaign_id`.
+    fn wants(&self, event: Event, campaign_id: Option<i64>) -> bool {
+        self.events().contains(&event)
+            && (self.campaign_id.is_none() || self.campaign_id == campaign_id)
+    }
+}
+
+/// A webhook as listed, without its secret.
+#[derive(Debug, Serialize)]
+struct Webhook {
+    id: i64,
+    url: String,
+    events: Vec<Event>,
+    campaign_id: Option<i64>,
+    threshold: Option<f64>,
+    files: Vec<String>,
+    creator: String,
+    created_at: i64,
+}
+
+impl From<WebhookRow> for Webhook {
+    fn from(row: WebhookRow) -> Self {
+        Webhook {
+            id: row.id,
+            events: row.events(),
+            files: row.files(),
+            url: row.url,
+            campaign_id: row.campaign_id,
+            threshold: row.threshold,
+            creator: row.creator,
+            created_at: row.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct NewWebhook {
+    url: String,
+    events: Vec<Event>,
+    /// Only events of this campaign are sent, instead of all of them.
+    campaign_id: Option<i64>,
+    /// Score, between 0 and 1, whose crossings are reported.
+    threshold: Option<f64>,
+    /// File or directory prefixes watched for new survivors.
+    #[serde(default)]
+    files: Vec<String>,
+    /// Generated when not given.
+    secret: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CreatedWebhook {
+    #[serde(flatten)]
+    webhook: Webhook,
+    /// Only ever returned here.
+    secret: String,
+}
+
+/// The JSON body POSTed to webhooks, named by its `event` field.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Payload {
+    CampaignCompleted {
+        campaign: Campaign,
+        score: Option<f64>,
+    },
+    ScoreThreshold {
+        campaign: Campaign,
+        score: f64,
+        threshold: f64,
+        above: bool,
+    },
+    NewSurvivor {
+        mutation: Box<Mutation>,
+    },
+}
+
+impl Payload {
+    fn event(&self) -> Event {
+        match self {
+            Payload::CampaignCompleted { .. } => Event::CampaignCompleted,
+            Payload::ScoreThreshold { .. } => Event::ScoreThreshold,
+            Payload::NewSurvivor { .. } => Event::NewSurvivor,
+        }
+    }
+}
+
+/// Signs a body the way GitHub does in `X-Hub-Signature-256`, so receivers
+/// can check it came from us with the usual libraries.
+pub(super) fn sign(secret: &str, body: &[u8]) -> String {
+    // HMAC accepts keys of any length.
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Sends a payload to a webhook in the background. Failed deliveries are
+/// logged and not retried.
+fn deliver(webhook: &WebhookRow, payload: &Payload) {
+    let body = match serde_json::to_vec(payload) {
+        Ok(body) => body,
+        Err(e) => {
+            println!("Error serializing webhook payload: {}", e);
+            return;
+        }
+    };
+    let signature = sign(&webhook.secret, &body);
+    let event = payload.event();
+    let id = webhook.id;
+    let url = webhook.url.clone();
+
+    actix_web::rt::spawn(async move {
+        let r = reqwest::Client::new()
+            .post(url)
+            .timeout(DELIVERY_TIMEOUT)
+            .header("Content-Type", "application/json")
+            .header("X-BCM-Event", event.to_string())
+            .header("X-Hub-Signature-256", signature)
+            .body(body)
+            .send()
+            .await;
+
+        match r {
+            Ok(res) if res.status().is_success() => println!("Sent {} to webhook {}", event, id),
+            Ok(res) => println!("Webhook {} rejected {}: {}", id, event, res.status()),
+            Err(e) => println!("Error sending {} to webhook {}: {}", event, id, e),
+        }
+    });
+}
+
+async fn fetch_webhooks(pool: &SqlitePool) -> Result<Vec<WebhookRow>, sqlx::Error> {
+    sqlx::query_as!(WebhookRow, "SELECT * FROM webhooks ORDER BY id")
+        .fetch_all(pool)
+        .await
+}
+
+/// Tells webhooks a campaign completed, with its final score.
+pub(super) async fn campaign_completed(pool: &SqlitePool, campaign_id: i64) {
+    if let Err(e) = notify_completion(pool, campaign_id).await {
+        println!(
+            "Error notifying completion of campaign {}: {}",
+            campaign_id, e
+        );
+    }
+}
+
+async fn notify_completion(pool: &SqlitePool, campaign_id: i64) -> Result<(), sqlx::Error> {
+    let webhooks = fetch_webhooks(pool).await?;
+    let webhooks: Vec<&WebhookRow> = webhooks
+        .iter()
+        .filter(|webhook| webhook.wants(Event::CampaignCompleted, Some(campaign_id)))
+        .collect();
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+
+    let payload = Payload::CampaignCompleted {
+        campaign: campaigns::fetch_campaign(pool, campaign_id).await?,
+        score: score::campaign_score(pool, campaign_id).await?,
+    };
+    for webhook in webhooks {
+        deliver(webhook, &payload);
+    }
+
+    Ok(())
+}
+
+/// Compares the score of a campaign whose mutations changed to the
+/// thresholds of webhooks, telling them when it is on the other side than
+/// the last time. The first side seen is only recorded, so a campaign does
+/// not cross a threshold with its first results.
+pub(super) async fn check_score(pool: &SqlitePool, campaign_id: i64) {
+    if let Err(e) = notify_crossing(pool, campaign_id).await {
+        println!("Error checking webhook thresholds: {}", e);
+    }
+}
+
+async fn notify_crossing(pool: &SqlitePool, campaign_id: i64) -> Result<(), sqlx::Error> {
+    let webhooks: Vec<WebhookRow> = fetch_webhooks(pool)
+        .await?
+        .into_iter()
+        .filter(|webhook| webhook.threshold.is_some())
+        .filter(|webhook| webhook.wants(Event::ScoreThreshold, Some(campaign_id)))
+        .collect();
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+
+    let campaign = campaigns::fetch_campaign(pool, campaign_id).await?;
+    if campaign.state == CampaignState::Archived.to_string() {
+        return Ok(());
+    }
+    let score = match score::campaign_score(pool, campaign_id).await? {
+        Some(score) => score,
+        None => return Ok(()),
+    };
+
+    for webhook in &webhooks {
+        let threshold = webhook.threshold.unwrap_or_default();
+        let above = score >= threshold;
+        let seen = sqlx::query_scalar!(
+            r#"SELECT above AS "above: bool" FROM webhook_scores WHERE webhook_id = ? AND campaign_id = ?"#,
+            webhook.id,
+            campaign_id
+        )
+        .fetch_optional(pool)
+        .await?;
+        if seen == Some(above) {
+            continue;
+        }
+
+        sqlx::query!(
+            "INSERT INTO webhook_scores (webhook_id, campaign_id, above) VALUES (?, ?, ?)
+            ON CONFLICT (webhook_id, campaign_id) DO UPDATE SET above = excluded.above",
+            webhook.id,
+            campaign_id,
+            above
+        )
+        .execute(pool)
+        .await?;
+
+        if seen.is_some() {
+            println!(
+                "Score of campaign {} went {} {} ({})",
+                campaign_id,
+                if above { "above" } else { "below" },
+                threshold,
+                score
+            );
+            let payload = Payload::ScoreThreshold {
+                campaign: campaign.clone(),
+                score,
+                threshold,
+                above,
+            };
+            deliver(webhook, &payload);
+        }
+    }
+
+    Ok(())
+}
+
+/// Tells webhooks watching its file about a mutation that just survived,
+/// unless it, or the same mutant in another campaign, survived before.
+pub(super) async fn mutation_survived(pool: &SqlitePool, id: i64) {
+    if let Err(e) = notify_survivor(pool, id).await {
+        println!("Error notifying survival of mutation {}: {}", id, e);
+    }
+}
+
+async fn notify_survivor(pool: &SqlitePool, id: i64) -> Result<(), sqlx::Error> {
+    let mutation = match fetch_mutation(pool, id).await? {
+        Some(mutation) => mutation,
+        None => return Ok(()),
+    };
+    let webhooks = fetch_webhooks(pool).await?;
+    let webhooks: Vec<&WebhookRow> = webhooks
+        .iter()
+        .filter(|webhook| webhook.wants(Event::NewSurvivor, mutation.campaign_id))
+        .filter(|webhook| {
+            webhook
+                .files()
+                .iter()
+                .any(|file| mutation.file.starts_with(file.as_str()))
+        })
+        .collect();
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+
+    // The result just recorded is one of them.
+    let survived = MutationStatus::NotKilled.to_string();
+    let survivals = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!: i64" FROM results r JOIN mutations m ON m.id = r.mutation_id
+        WHERE (m.id = ? OR m.fingerprint = ?) AND r.status = ?"#,
+        id,
+        mutation.fingerprint,
+        survived
+    )
+    .fetch_one(pool)
+    .await?;
+    if survivals > 1 {
+        return Ok(());
+    }
+
+    println!("Mutation {} is a new survivor in {}", id, mutation.file);
+    let payload = Payload::NewSurvivor {
+        mutation: Box::new(mutation),
+    };
+    for webhook in webhooks {
+        deliver(webhook, &payload);
+    }
+
+    Ok(())
+}
+
+#[post("/webhooks")]
+async fn create_webhook(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    new_webhook: web::Json<NewWebhook>,
+) -> Result<HttpResponse, ServerError> {
+    let admin = authorize(&request, &ctx, Role::Admin).await?;
+
+    let new_webhook = new_webhook.into_inner();
+    if !new_webhook.url.starts_with("http://") && !new_webhook.url.starts_with("https://") {
+        return Err(ServerError::BadRequest(format!(
+            "Not an HTTP URL: {}",
+            new_webhook.url
+        )));
+    }
+    if new_webhook.events.is_empty() {
+        return Err(ServerError::BadRequest(
+            "A webhook needs at least one event".to_string(),
+        ));
+    }
+    let files: Vec<String> = new_webhook
+        .files
+        .iter()
+        .map(|file| file.trim().to_string())
+        .filter(|file| !file.is_empty())
+        .collect();
+    if new_webhook.events.contains(&Event::ScoreThreshold)
+        && !matches!(new_webhook.threshold, Some(t) if (0.0..=1.0).contains(&t))
+    {
+        return Err(ServerError::BadRequest(
+            "score_threshold needs a threshold between 0 and 1".to_string(),
+        ));
+    }
+    if new_webhook.events.contains(&Event::NewSurvivor) && files.is_empty() {
+        return Err(ServerError::BadRequest(
+            "new_survivor needs files to watch".to_string(),
+        ));
+    }
+    if let Some(campaign_id) = new_webhook.campaign_id {
+        if !campaigns::campaign_exists(&ctx.pool, campaign_id).await? {
+            return Err(ServerError::BadRequest(format!(
+                "Campaign {} does not exist",
+                campaign_id
+            )));
+        }
+    }
+
+    let secret = new_webhook
+        .secret
+        .unwrap_or_else(|| hex::encode(rand::thread_rng().gen::<[u8; 32]>()));
+    let events = serde_json::to_string(&new_webhook.events).unwrap_or_default();
+    let files = serde_json::to_string(&files).unwrap_or_default();
+    let now = chrono::Utc::now().timestamp();
+    let id = sqlx::query!(
+        "INSERT INTO webhooks (url, secret, events, campaign_id, threshold, files, creator, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        new_webhook.url,
+        secret,
+        events,
+        new_webhook.campaign_id,
+        new_webhook.threshold,
+        files,
+        admin,
+        now
+    )
+    .execute(&ctx.pool)
+    .await?
+    .last_insert_rowid();
+    println!("Webhook {} to {} created by {}", id, new_webhook.url, admin);
+
+    let webhook = sqlx::query_as!(WebhookRow, "SELECT * FROM webhooks WHERE id = ?", id)
+        .fetch_one(&ctx.pool)
+        .await?;
+    Ok(HttpResponse::Ok().json(CreatedWebhook {
+        webhook: webhook.into(),
+        secret,
+    }))
+}
+
+#[get("/webhooks")]
+async fn list_webhooks(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+) -> Result<HttpResponse, ServerError> {
+    authorize(&request, &ctx, Role::Admin).await?;
+
+    let webhooks: Vec<Webhook> = fetch_webhooks(&ctx.pool)
+        .await?
+        .into_iter()
+        .map(Webhook::from)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(webhooks))
+}
+
+#[delete("/webhooks/{id}")]
+async fn delete_webhook(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ServerError> {
+    let admin = authorize(&request, &ctx, Role::Admin).await?;
+
+    let id = id.into_inner();
+    let mut tx = ctx.pool.begin().await?;
+    sqlx::query!("DELETE FROM webhook_scores WHERE webhook_id = ?", id)
+        .execute(&mut tx)
+        .await?;
+    let r = sqlx::query!("DELETE FROM webhooks WHERE id = ?", id)
+        .execute(&mut tx)
+        .await?;
+    if r.rows_affected() == 0 {
+        return Err(ServerError::NotFound(format!("Webhook {} not found", id)));
+    }
+    tx.commit().await?;
+    println!("Webhook {} deleted by {}", id, admin);
+
+    Ok(HttpResponse::Ok().body("Webhook deleted"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test as actix_test;
+    use actix_web::{App, HttpServer};
+    use common::MutationResult;
+
+    use crate::server::testing::{init_app, test_pool};
+
+    #[test]
+    fn test_payloads_are_signed_like_github() {
+        // The example from GitHub's documentation on validating deliveries.
+        assert_eq!(
+            sign("It's a Secret to Everybody", b"Hello, World!"),
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"
+        );
+    }
+
+    /// Stands in for a webhook receiver, passing on the event, signature and
+    /// body of every delivery.
+    fn webhook_receiver() -> (
+        String,
+        tokio::sync::mpsc::UnboundedReceiver<(String, String, web::Bytes)>,
+    ) {
+        type Deliveries = tokio::sync::mpsc::UnboundedSender<(String, String, web::Bytes)>;
+
+        async fn receive(
+            request: HttpRequest,
+            body: web::Bytes,
+            deliveries: web::Data<Deliveries>,
+        ) -> HttpResponse {
+            let header = |name| {
+                request
+                    .headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            let _ = deliveries.send((header("X-BCM-Event"), header("X-Hub-Signature-256"), body));
+            HttpResponse::Ok().finish()
+        }
+
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(sender.clone()))
+                .default_service(web::to(receive))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}/hook", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+
+        (url, receiver)
+    }
+
+    #[actix_web::test]
+    async fn test_webhooks_are_signed_and_sent() {
+        let (pool, _db) = test_pool().await;
+        let campaign_id = sqlx::query(
+            "INSERT INTO campaigns (name, source, creator, created_at, state) VALUES ('wallet', 'files', 'carol', 0, 'Open')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap()
+        .last_insert_rowid();
+        for (md5, file) in [
+            ("md5-net", "src/net.cpp"),
+            ("md5-wallet", "src/wallet/wallet.cpp"),
+        ] {
+            sqlx::query(
+                "INSERT INTO mutations (patch_md5, file, line, patch, status, fingerprint, campaign_id) VALUES (?, ?, 1, '', ?, ?, ?)",
+            )
+            .bind(md5)
+            .bind(file)
+            .bind(MutationStatus::Pending.to_string())
+            .bind(format!("fp-{}", md5))
+            .bind(campaign_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+        let app = init_app(&pool).await;
+        let (url, mut deliveries) = webhook_receiver();
+
+        for (token, webhook, status) in [
+            (
+                "carol-submitter",
+                serde_json::json!({"url": url, "events": ["campaign_completed"]}),
+                403,
+            ),
+            (
+                "root-admin",
+                serde_json::json!({"url": url, "events": ["score_threshold"]}),
+                400,
+            ),
+            (
+                "root-admin",
+                serde_json::json!({
+                    "url": url,
+                    "events": ["campaign_completed", "score_threshold", "new_survivor"],
+                    "threshold": 0.75,
+                    "files": ["src/wallet/"],
+                    "secret": "hunter2",
+                }),
+                200,
+            ),
+        ] {
+            let req = actix_test::TestRequest::post()
+                .uri("/webhooks")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(webhook)
+                .to_request();
+            assert_eq!(actix_test::call_service(&app, req).await.status(), status);
+        }
+
+        let req = actix_test::TestRequest::get()
+            .uri("/webhooks")
+            .insert_header(("Authorization", "Bearer root-admin"))
+            .to_request();
+        let webhooks: Vec<serde_json::Value> = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(webhooks.len(), 1);
+        assert!(webhooks[0].get("secret").is_none());
+
+        // A kill first puts the score above the threshold, which is not a
+        // crossing. The survivor in the wallet then brings it below and
+        // completes the campaign.
+        for status in [MutationStatus::Killed, MutationStatus::NotKilled] {
+            let req = actix_test::TestRequest::post()
+                .uri("/get_work")
+                .insert_header(("Authorization", "Bearer bob-worker"))
+                .to_request();
+            let mutation: Mutation = actix_test::call_and_read_body_json(&app, req).await;
+
+            let req = actix_test::TestRequest::post()
+                .uri(&format!("/mutations/{}", mutation.id))
+                .insert_header(("Authorization", "Bearer bob-worker"))
+                .set_json(MutationResult {
+                    mutation_id: mutation.patch_md5.clone(),
+                    status,
+                    stdout: None,
+                    stderr: None,
+                    exit_code: None,
+                })
+                .to_request();
+            assert_eq!(actix_test::call_service(&app, req).await.status(), 200);
+        }
+
+        let mut received = vec![];
+        for _ in 0..3 {
+            let (event, signature, body) =
+                tokio::time::timeout(std::time::Duration::from_secs(10), deliveries.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(signature, sign("hunter2", &body));
+            let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(payload["event"], event);
+            received.push((event, payload));
+        }
+        received.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let events: Vec<&str> = received.iter().map(|(event, _)| event.as_str()).collect();
+        assert_eq!(
+            events,
+            ["campaign_completed", "new_survivor", "score_threshold"]
+        );
+        assert_eq!(received[0].1["campaign"]["state"], "Completed");
+        assert_eq!(received[0].1["score"], 0.5);
+        assert_eq!(received[1].1["mutation"]["file"], "src/wallet/wallet.cpp");
+        assert_eq!(received[2].1["above"], false);
+    }
+}
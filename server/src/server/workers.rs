@@ -0,0 +1,383 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use common::{MutationStatus, Worker, WorkerRegistration};
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqlitePool;
+
+use super::capabilities;
+use super::tokens::{authorize, authorize_read, Role};
+use super::Context;
+use crate::errors::ServerError;
+
+#[derive(Debug, Deserialize)]
+struct WorkerParams {
+    worker_id: Option<i64>,
+}
+
+/// Returns the registered worker a request was sent from, given as
+/// `?worker_id=` by workers started after the registry existed.
+pub(super) fn worker_id(request: &HttpRequest) -> Option<i64> {
+    web::Query::<WorkerParams>::from_query(request.query_string())
+        .ok()
+        .and_then(|params| params.worker_id)
+}
+
+struct WorkerRow {
+    id: i64,
+    owner: String,
+    hostname: String,
+    version: String,
+    capabilities: String,
+    current_mutation: Option<i64>,
+    registered_at: i64,
+    last_heartbeat: i64,
+    killed: i64,
+    not_killed: i64,
+    timeouts: i64,
+    errors: i64,
+    expired_leases: i64,
+}
+
+impl From<WorkerRow> for Worker {
+    fn from(row: WorkerRow) -> Self {
+        Worker {
+            id: row.id,
+            owner: row.owner,
+            hostname: row.hostname,
+            version: row.version,
+            capabilities: serde_json::from_str(&row.capabilities).unwrap_or_default(),
+            current_mutation: row.current_mutation,
+            registered_at: row.registered_at,
+            last_heartbeat: row.last_heartbeat,
+            killed: row.killed,
+            not_killed: row.not_killed,
+            timeouts: row.timeouts,
+            errors: row.errors,
+            expired_leases: row.expired_leases,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct WorkerStatus {
+    #[serde(flatten)]
+    worker: Worker,
+    state: &'static str,
+}
+
+/// Registers a worker, or refreshes it when the same owner restarts a worker
+/// on the same host, keeping its lifetime counts.
+#[post("/workers")]
+async fn register_worker(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+    registration: web::Json<WorkerRegistration>,
+) -> Result<HttpResponse, ServerError> {
+    let owner = authorize(&request, &ctx, Role::Worker).await?;
+
+    let registration = registration.into_inner();
+    let capabilities = capabilities::to_json(&registration.capabilities);
+    let now = chrono::Utc::now().timestamp();
+
+    sqlx::query!(
+        "INSERT INTO workers (owner, hostname, version, capabilities, registered_at, last_heartbeat) VALUES (?, ?, ?, ?, ?, ?)
+        ON CONFLICT (owner, hostname) DO UPDATE SET version = excluded.version, capabilities = excluded.capabilities, current_mutation = NULL, last_heartbeat = excluded.last_heartbeat",
+        owner,
+        registration.hostname,
+        registration.version,
+        capabilities,
+        now,
+        now
+    )
+    .execute(&ctx.pool)
+    .await?;
+
+    let id = sqlx::query!(
+        "SELECT id FROM workers WHERE owner = ? AND hostname = ?",
+        owner,
+        registration.hostname
+    )
+    .fetch_one(&ctx.pool)
+    .await?
+    .id;
+
+    println!(
+        "Worker {} registered by {} from {} ({})",
+        id, owner, registration.hostname, registration.version
+    );
+
+    let worker = fetch_worker(&ctx.pool, id).await?;
+    Ok(HttpResponse::Ok().json(worker))
+}
+
+#[get("/workers")]
+async fn list_workers(
+    request: HttpRequest,
+    ctx: web::Data<Context>,
+) -> Result<HttpResponse, ServerError> {
+    authorize_read(&request, &ctx).await?;
+
+    let rows = sqlx::query_as!(
+        WorkerRow,
+        "SELECT * FROM workers ORDER BY last_heartbeat DESC"
+    )
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    // A worker polls for work well within a lease when it is idle, so one
+    // silent for longer than a lease is considered gone.
+    let now = chrono::Utc::now().timestamp();
+    let workers: Vec<WorkerStatus> = rows
+        .into_iter()
+        .map(|row| {
+            let worker = Worker::from(row);
+            let state = if now - worker.last_heartbeat > ctx.lease_duration {
+                "offline"
+            } else if worker.current_mutation.is_some() {
+                "busy"
+            } else {
+                "idle"
+            };
+
+            WorkerStatus { worker, state }
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(workers))
+}
+
+async fn fetch_worker(pool: &SqlitePool, id: i64) -> Result<Worker, sqlx::Error> {
+    let row = sqlx::query_as!(WorkerRow, "SELECT * FROM workers WHERE id = ?", id)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(row.into())
+}
+
+/// Records that a worker is alive and what it is working on. `None` leaves
+/// the current mutation as is.
+pub(super) async fn record_heartbeat(
+    pool: &SqlitePool,
+    worker_id: i64,
+    owner: &str,
+    current_mutation: Option<i64>,
+) {
+    let r = sqlx::query(
+        "UPDATE workers SET last_heartbeat = ?, current_mutation = COALESCE(?, current_mutation) WHERE id = ? AND owner = ?",
+    )
+    .bind(chrono::Utc::now().timestamp())
+    .bind(current_mutation)
+    .bind(worker_id)
+    .bind(owner)
+    .execute(pool)
+    .await;
+
+    if let Err(e) = r {
+        println!("Error updating worker {}: {}", worker_id, e);
+    }
+}
+
+/// Counts a result reported by a worker and marks it as idle.
+pub(super) async fn record_result(
+    pool: &SqlitePool,
+    worker_id: i64,
+    owner: &str,
+    status: &MutationStatus,
+) {
+    let column = match status {
+        MutationStatus::Killed => "killed",
+        MutationStatus::NotKilled => "not_killed",
+        MutationStatus::Timeout => "timeouts",
+        _ => "errors",
+    };
+
+    let r = sqlx::query(&format!(
+        "UPDATE workers SET {0} = {0} + 1, current_mutation = NULL, last_heartbeat = ? WHERE id = ? AND owner = ?",
+        column
+    ))
+    .bind(chrono::Utc::now().timestamp())
+    .bind(worker_id)
+    .bind(owner)
+    .execute(pool)
+    .await;
+
+    if let Err(e) = r {
+        println!("Error updating worker {}: {}", worker_id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+    use common::{Mutation, MutationResult};
+
+    use crate::server::testing::{init_app, insert_pending, test_pool};
+
+    #[actix_web::test]
+    async fn test_workers_track_their_mutations() {
+        let (pool, _db) = test_pool().await;
+        insert_pending(&pool, 1).await;
+
+        let app = init_app(&pool).await;
+
+        let req = test::TestRequest::post()
+            .uri("/workers")
+            .insert_header(("Authorization", "secret"))
+            .set_json(common::WorkerRegistration {
+                hostname: "builder-1".to_string(),
+                version: "0.1.0".to_string(),
+                capabilities: vec!["tsan".to_string()],
+            })
+            .to_request();
+        let worker: common::Worker = test::call_and_read_body_json(&app, req).await;
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/get_work?worker_id={}", worker.id))
+            .insert_header(("Authorization", "secret"))
+            .to_request();
+        let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+
+        let req = test::TestRequest::get().uri("/workers").to_request();
+        let workers: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(workers[0]["state"], "busy");
+        assert_eq!(workers[0]["current_mutation"], mutation.id);
+        assert_eq!(workers[0]["capabilities"], serde_json::json!(["tsan"]));
+
+        // Only the first report is counted, the second is refused as the
+        // lease is gone.
+        for expected in [200, 409] {
+            let req = test::TestRequest::post()
+                .uri(&format!(
+                    "/mutations/{}?worker_id={}",
+                    mutation.id, worker.id
+                ))
+                .insert_header(("Authorization", "secret"))
+                .set_json(MutationResult {
+                    mutation_id: mutation.patch_md5.clone(),
+                    status: MutationStatus::Killed,
+                    stdout: None,
+                    stderr: None,
+                    exit_code: None,
+                })
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), expected);
+        }
+
+        let req = test::TestRequest::get().uri("/workers").to_request();
+        let workers: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(workers[0]["state"], "idle");
+        assert_eq!(workers[0]["killed"], 1);
+    }
+
+    #[actix_web::test]
+    async fn test_idle_workers_stay_online_while_polling() {
+        let (pool, _db) = test_pool().await;
+        let app = init_app(&pool).await;
+
+        let req = test::TestRequest::post()
+            .uri("/workers")
+            .insert_header(("Authorization", "bob-worker"))
+            .set_json(common::WorkerRegistration {
+                hostname: "builder-2".to_string(),
+                version: "0.1.0".to_string(),
+                capabilities: vec![],
+            })
+            .to_request();
+        let worker: common::Worker = test::call_and_read_body_json(&app, req).await;
+
+        // Last heard of long ago, as if the queue had been empty for hours.
+        sqlx::query("UPDATE workers SET last_heartbeat = 0 WHERE id = ?")
+            .bind(worker.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let req = test::TestRequest::get().uri("/workers").to_request();
+        let workers: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(workers[0]["state"], "offline");
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/get_work?worker_id={}", worker.id))
+            .insert_header(("Authorization", "bob-worker"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 204);
+
+        let req = test::TestRequest::get().uri("/workers").to_request();
+        let workers: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(workers[0]["state"], "idle");
+    }
+
+    #[actix_web::test]
+    async fn test_leases_are_held_by_the_claiming_worker() {
+        let (pool, _db) = test_pool().await;
+        insert_pending(&pool, 1).await;
+        let app = init_app(&pool).await;
+
+        // Two machines sharing a token.
+        let mut ids = vec![];
+        for hostname in ["builder-1", "builder-2"] {
+            let req = test::TestRequest::post()
+                .uri("/workers")
+                .insert_header(("Authorization", "bob-worker"))
+                .set_json(common::WorkerRegistration {
+                    hostname: hostname.to_string(),
+                    version: "0.1.0".to_string(),
+                    capabilities: vec![],
+                })
+                .to_request();
+            let worker: common::Worker = test::call_and_read_body_json(&app, req).await;
+            ids.push(worker.id);
+        }
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/get_work?worker_id={}", ids[0]))
+            .insert_header(("Authorization", "bob-worker"))
+            .to_request();
+        let mutation: Mutation = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(mutation.worker_id, Some(ids[0]));
+
+        for (worker_id, expected) in [(ids[1], 409), (ids[0], 200)] {
+            let req = test::TestRequest::post()
+                .uri(&format!(
+                    "/mutations/{}/heartbeat?worker_id={}",
+                    mutation.id, worker_id
+                ))
+                .insert_header(("Authorization", "bob-worker"))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), expected);
+
+            let req = test::TestRequest::post()
+                .uri(&format!(
+                    "/mutation/{}/live?worker_id={}&stream=stdout",
+                    mutation.id, worker_id
+                ))
+                .insert_header(("Authorization", "bob-worker"))
+                .set_payload("building\n")
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), expected);
+        }
+
+        for (query, expected) in [
+            (format!("?worker_id={}", ids[1]), 409),
+            (String::new(), 409),
+            (format!("?worker_id={}", ids[0]), 200),
+        ] {
+            let req = test::TestRequest::post()
+                .uri(&format!("/mutations/{}{}", mutation.id, query))
+                .insert_header(("Authorization", "bob-worker"))
+                .set_json(MutationResult {
+                    mutation_id: mutation.patch_md5.clone(),
+                    status: MutationStatus::Killed,
+                    stdout: None,
+                    stderr: None,
+                    exit_code: None,
+                })
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), expected);
+        }
+
+        let req = test::TestRequest::get().uri("/workers").to_request();
+        let workers: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        let killed: i64 = workers.iter().map(|w| w["killed"].as_i64().unwrap()).sum();
+        assert_eq!(killed, 1);
+    }
+}
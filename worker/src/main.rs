@@ -1,5 +1,5 @@
 use clap::Parser;
-mod run;
+use worker::run;
 
 #[derive(Parser, Default)]
 #[command(
@@ -29,6 +29,28 @@ struct Args {
     token: String,
     #[clap(long, help = "Timeout (seconds)", default_value = "1800")]
     timeout: u64,
+    #[clap(
+        long,
+        help = "Seconds between heartbeats sent while running a mutation",
+        default_value = "60"
+    )]
+    heartbeat_interval: u64,
+    #[clap(
+        long = "capability",
+        help = "Capabilities of this worker (e.g. tsan, wallet, qt, fuzz)"
+    )]
+    capabilities: Vec<String>,
+    #[clap(
+        long,
+        help = "Mutations sharing a checkout to run per batch",
+        default_value = "1"
+    )]
+    batch: usize,
+    #[clap(
+        long,
+        help = "Do not send the output of running mutations to the server until they finish"
+    )]
+    no_live: bool,
 }
 
 #[actix_web::main]
@@ -41,15 +63,25 @@ async fn main() {
     })
     .expect("Error setting Ctrl-C handler");
 
-    let r = run::execute_mutations(
-        &args.server,
-        &args.path,
-        &args.build_cmd,
-        &args.test_cmd,
-        args.timeout,
-        &args.token,
-    )
-    .await;
+    let config = run::Config {
+        server: args.server,
+        token: args.token,
+        path: args.path,
+        build_cmd: args.build_cmd,
+        test_cmd: args.test_cmd,
+        timeout: args.timeout,
+        heartbeat_interval: args.heartbeat_interval,
+        capabilities: args.capabilities,
+        batch: args.batch,
+        live: !args.no_live,
+    };
+
+    let worker_id = match run::register_worker(&config).await {
+        Ok(worker_id) => worker_id,
+        Err(e) => panic!("Failed to register worker: {}", e),
+    };
+
+    let r = run::execute_mutations(&config, worker_id).await;
 
     if r.is_err() {
         panic!("Worker failed with error: {}", r.unwrap_err());
@@ -1,147 +1,596 @@
 use std::io::{BufRead, BufReader};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use wait_timeout::ChildExt;
 
-use common::{Mutation, MutationResult, MutationStatus};
+use common::{Mutation, MutationResult, MutationStatus, Worker, WorkerRegistration};
+
+pub struct Config {
+    pub server: String,
+    pub token: String,
+    pub path: String,
+    pub build_cmd: String,
+    pub test_cmd: String,
+    pub timeout: u64,
+    pub heartbeat_interval: u64,
+    pub capabilities: Vec<String>,
+    /// Mutations sharing a checkout to ask for at once. Up to 1, a single
+    /// mutation is asked for the way older servers expect.
+    pub batch: usize,
+    /// Whether to send the output of running mutations to the server while
+    /// they run, for `/mutation/{id}/live` followers.
+    pub live: bool,
+}
+
+/// Announces this worker to the server and returns the id it was given.
+pub async fn register_worker(config: &Config) -> Result<i64, Box<dyn std::error::Error>> {
+    let registration = WorkerRegistration {
+        hostname: gethostname::gethostname().to_string_lossy().to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        capabilities: config.capabilities.clone(),
+    };
+
+    let client = reqwest::Client::new();
+    let res = client
+        .post(format!("{}/workers", config.server))
+        .body(serde_json::to_string(&registration)?)
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}", config.token))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let worker = serde_json::from_str::<Worker>(&res.text().await?)?;
+    println!("Registered as worker {} ({})", worker.id, worker.hostname);
+
+    Ok(worker.id)
+}
 
 pub async fn execute_mutations(
-    server: &str,
-    path: &str,
-    build_cmd: &str,
-    test_cmd: &str,
-    timeout: u64,
-    token: &str,
+    config: &Config,
+    worker_id: i64,
 ) -> Result<(), Box<dyn std::error::Error>> {
     println!("Running mutations...");
 
     loop {
         println!("Getting work...");
-        let client = reqwest::Client::new();
-        let res = client
-            .post(&format!("{}/get_work", server))
-            .header("Authorization", token)
-            .send()
-            .await?;
-        if res.status() == 204 {
+        let mutations = get_work(config, worker_id).await?;
+        if mutations.is_empty() {
             println!("No work available");
             std::thread::sleep(std::time::Duration::from_secs(120));
             continue;
         }
 
-        let mutation = serde_json::from_str::<Mutation>(&res.text().await?)?;
-        println!("Got work: {}", mutation.id);
-
-        // Execute shell commands
-        let mut cmd = std::process::Command::new("bash");
-        cmd.current_dir(path);
-        cmd.arg("-c");
-        cmd.stdout(std::process::Stdio::piped());
-        cmd.stderr(std::process::Stdio::piped());
-
-        // format!(
-        //     "git reset --hard && git checkout {} && git pull origin {}",
-        //     branch, branch
-        // );
-        let mut cmd_str = {
-            if let Some(pr) = mutation.pr_number {
-                format!(
-                    "git reset --hard && git checkout master && git branch | grep -v master | xargs git branch -D; gh pr checkout {} && git rebase --abort; git pull origin master --rebase",
-                    pr,
-                )
-            } else {
-                let branch = mutation.branch.as_ref().unwrap();
-                format!(
-                    "git reset --hard && git checkout master && git checkout {} && git pull origin {}",
-                    branch, branch
-                )
+        let ids: Vec<i64> = mutations.iter().map(|mutation| mutation.id).collect();
+        println!("Got work: {:?}", ids);
+
+        let heartbeat = Heartbeat::start(
+            &config.server,
+            &config.token,
+            worker_id,
+            ids,
+            config.heartbeat_interval,
+        );
+        let r = execute_batch(config, worker_id, &mutations, &heartbeat).await;
+        heartbeat.stop();
+        r?;
+    }
+}
+
+/// Asks the server for work, returning no mutation when there is none.
+async fn get_work(
+    config: &Config,
+    worker_id: i64,
+) -> Result<Vec<Mutation>, Box<dyn std::error::Error>> {
+    let mut url = format!("{}/get_work?worker_id={}", config.server, worker_id);
+    if config.batch > 1 {
+        url = format!("{}&batch={}", url, config.batch);
+    }
+
+    let client = reqwest::Client::new();
+    let res = client
+        .post(url)
+        .header("Authorization", format!("Bearer {}", config.token))
+        .send()
+        .await?;
+    if res.status() == 204 {
+        return Ok(vec![]);
+    }
+
+    let body = res.text().await?;
+    if config.batch > 1 {
+        Ok(serde_json::from_str::<Vec<Mutation>>(&body)?)
+    } else {
+        Ok(vec![serde_json::from_str::<Mutation>(&body)?])
+    }
+}
+
+/// Checks out the base shared by a batch of mutations once, then applies,
+/// builds, tests and reverts each of them, reporting each result as soon as
+/// it is known.
+async fn execute_batch(
+    config: &Config,
+    worker_id: i64,
+    mutations: &[Mutation],
+    heartbeat: &Heartbeat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (checkout_ok, checkout_stdout, checkout_stderr) =
+        run_command(&config.path, &checkout_command(&mutations[0]))?;
+
+    for mutation in mutations {
+        let result = if checkout_ok {
+            let live = config
+                .live
+                .then(|| LiveLog::start(&config.server, &config.token, worker_id, mutation.id));
+            let result = run_mutation(
+                mutation,
+                &config.path,
+                &format!("{} && {}", config.build_cmd, config.test_cmd),
+                config.timeout,
+                live.as_ref(),
+            );
+            if let Some(live) = live {
+                live.stop();
             }
+            revert_patch(&config.path)?;
+            result?
+        } else {
+            println!("Failed to check out the base of mutation {}", mutation.id);
+            checkout_error(mutation, &checkout_stdout, &checkout_stderr)
         };
+        heartbeat.finish(mutation.id);
 
-        // Store patch
-        let patch_path = format!("/tmp/{}.patch", mutation.id);
-        std::fs::write(&patch_path, mutation.patch)?;
-        cmd_str = format!("{} && patch {} {}", cmd_str, mutation.file, patch_path);
-        cmd_str = format!("{} && {}", cmd_str, build_cmd);
-        cmd_str = format!("{} && {}", cmd_str, test_cmd);
-        cmd.arg(cmd_str.clone());
-
-        let mut child = cmd.spawn().unwrap();
-
-        // Stream stdout and stderr
-        let stdout = child.stdout.take().unwrap();
-        let stderr = child.stderr.take().unwrap();
-        let stdout = BufReader::new(stdout);
-        let stderr = BufReader::new(stderr);
-
-        // separate thread to read stdout and stderr
-        let stdout_handle = std::thread::spawn(move || {
-            let mut stdout_str = String::new();
-            for line in stdout.lines() {
-                let line = line.unwrap();
-                stdout_str = format!("{}\n{}", stdout_str, line);
-                println!("stdout: {}", line);
-            }
+        println!("Mutation {} status: {:?}", mutation.id, result.status);
+        submit_result(config, worker_id, mutation, &result).await?;
+    }
 
-            stdout_str
+    Ok(())
+}
+
+async fn submit_result(
+    config: &Config,
+    worker_id: i64,
+    mutation: &Mutation,
+    result: &MutationResult,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = reqwest::Client::new();
+    let res = client
+        .post(format!(
+            "{}/mutations/{}?worker_id={}",
+            config.server, mutation.id, worker_id
+        ))
+        .body(serde_json::to_string(result)?)
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}", config.token))
+        .send()
+        .await;
+
+    match res {
+        Ok(res) => println!("Mutation status sent: {}", res.status()),
+        Err(e) => println!("Error sending mutation status: {}", e),
+    }
+
+    Ok(())
+}
+
+/// Keeps the leases on the mutations of a batch alive until each of them is
+/// executed, so the server does not hand them out again.
+struct Heartbeat {
+    stop: Arc<AtomicBool>,
+    pending: Arc<Mutex<Vec<i64>>>,
+    handle: std::thread::JoinHandle<()>,
+}
+
+impl Heartbeat {
+    fn start(
+        server: &str,
+        token: &str,
+        worker_id: i64,
+        mutation_ids: Vec<i64>,
+        interval: u64,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let pending = Arc::new(Mutex::new(mutation_ids));
+        let server = server.to_string();
+        let token = token.to_string();
+
+        let thread_stop = stop.clone();
+        let thread_pending = pending.clone();
+        let handle = std::thread::spawn(move || {
+            let client = reqwest::blocking::Client::new();
+            let mut elapsed = 0;
+            while !thread_stop.load(Ordering::Relaxed) {
+                std::thread::sleep(std::time::Duration::from_secs(1));
+                elapsed += 1;
+                if elapsed < interval {
+                    continue;
+                }
+                elapsed = 0;
+
+                let mutation_ids = thread_pending.lock().unwrap().clone();
+                for mutation_id in mutation_ids {
+                    match client
+                        .post(format!(
+                            "{}/mutations/{}/heartbeat?worker_id={}",
+                            server, mutation_id, worker_id
+                        ))
+                        .header("Authorization", format!("Bearer {}", token))
+                        .send()
+                    {
+                        Ok(res) if res.status().is_success() => {}
+                        Ok(res) => println!("Heartbeat rejected: {}", res.status()),
+                        Err(e) => println!("Error sending heartbeat: {}", e),
+                    }
+                }
+            }
         });
 
-        let stderr_handle = std::thread::spawn(move || {
-            let mut stderr_str = String::new();
-            for line in stderr.lines() {
-                let line = line.unwrap();
-                stderr_str = format!("{}\n{}", stderr_str, line);
-                println!("stderr: {}", line);
+        Heartbeat {
+            stop,
+            pending,
+            handle,
+        }
+    }
+
+    /// Stops extending the lease of a mutation once it was executed.
+    fn finish(&self, mutation_id: i64) {
+        self.pending.lock().unwrap().retain(|id| *id != mutation_id);
+    }
+
+    fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.handle.join();
+    }
+}
+
+/// Output lines read from a running mutation, with the stream they came from.
+type OutputLine = (&'static str, String);
+
+/// Forwards the output of a running mutation to the server in chunks, so it
+/// can be followed before the result is in.
+struct LiveLog {
+    lines: mpsc::Sender<OutputLine>,
+    handle: std::thread::JoinHandle<()>,
+}
+
+impl LiveLog {
+    /// Time output is buffered before it is sent.
+    const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+    /// Output buffered on a stream that is sent without waiting.
+    const MAX_CHUNK: usize = 64 * 1024;
+
+    fn start(server: &str, token: &str, worker_id: i64, mutation_id: i64) -> Self {
+        let (lines, received) = mpsc::channel::<OutputLine>();
+        let url = format!(
+            "{}/mutation/{}/live?worker_id={}",
+            server, mutation_id, worker_id
+        );
+        let token = token.to_string();
+
+        let handle = std::thread::spawn(move || {
+            let client = reqwest::blocking::Client::new();
+            let send = |stream: &str, chunk: &mut String| {
+                if chunk.is_empty() {
+                    return;
+                }
+                match client
+                    .post(format!("{}&stream={}", url, stream))
+                    .body(std::mem::take(chunk))
+                    .header("Authorization", format!("Bearer {}", token))
+                    .send()
+                {
+                    Ok(res) if res.status().is_success() => {}
+                    Ok(res) => println!("Live output rejected: {}", res.status()),
+                    Err(e) => println!("Error sending live output: {}", e),
+                }
+            };
+
+            let mut stdout = String::new();
+            let mut stderr = String::new();
+            let mut deadline = Instant::now() + Self::FLUSH_INTERVAL;
+            loop {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                let done = match received.recv_timeout(timeout) {
+                    Ok((stream, line)) => {
+                        let chunk = if stream == "stderr" {
+                            &mut stderr
+                        } else {
+                            &mut stdout
+                        };
+                        chunk.push_str(&line);
+                        chunk.push('\n');
+                        if chunk.len() < Self::MAX_CHUNK && Instant::now() < deadline {
+                            continue;
+                        }
+                        false
+                    }
+                    Err(RecvTimeoutError::Timeout) => false,
+                    Err(RecvTimeoutError::Disconnected) => true,
+                };
+
+                send("stdout", &mut stdout);
+                send("stderr", &mut stderr);
+                if done {
+                    break;
+                }
+                deadline = Instant::now() + Self::FLUSH_INTERVAL;
             }
+        });
 
-            stderr_str
+        LiveLog { lines, handle }
+    }
+
+    /// Sends what is left once the mutation is done.
+    fn stop(self) {
+        drop(self.lines);
+        let _ = self.handle.join();
+    }
+}
+
+fn checkout_error(mutation: &Mutation, stdout: &str, stderr: &str) -> MutationResult {
+    MutationResult {
+        mutation_id: mutation.patch_md5.clone(),
+        status: MutationStatus::Error,
+        stdout: Some(stdout.to_string()),
+        stderr: Some(stderr.to_string()),
+        exit_code: None,
+    }
+}
+
+/// Applies the patch of a mutation on the current checkout, then builds and
+/// tests it.
+pub fn test_mutation(
+    mutation: &Mutation,
+    path: &str,
+    build_cmd: &str,
+    test_cmd: &str,
+    timeout: u64,
+) -> Result<MutationResult, Box<dyn std::error::Error>> {
+    run_mutation(
+        mutation,
+        path,
+        &format!("{} && {}", build_cmd, test_cmd),
+        timeout,
+        None,
+    )
+}
+
+fn run_mutation(
+    mutation: &Mutation,
+    path: &str,
+    cmd_str: &str,
+    timeout: u64,
+    live: Option<&LiveLog>,
+) -> Result<MutationResult, Box<dyn std::error::Error>> {
+    // A patch that does not apply on its own base commit means the code it
+    // was generated for is gone: report it instead of counting it as killed.
+    let (patched, stdout, stderr) = apply_patch(mutation, path)?;
+    if !patched {
+        println!("Patch of mutation {} does not apply", mutation.id);
+        return Ok(MutationResult {
+            mutation_id: mutation.patch_md5.clone(),
+            status: MutationStatus::Stale,
+            stdout: Some(stdout),
+            stderr: Some(stderr),
+            exit_code: None,
         });
+    }
 
-        let status = match child
-            .wait_timeout(std::time::Duration::from_secs(timeout))
-            .unwrap()
-        {
-            Some(status) => status.code(),
-            None => {
-                println!("Timeout reached, killing process");
-                // close stdout and stderr
-                drop(&stdout_handle);
-                drop(&stderr_handle);
+    let lines = live.map(|live| live.lines.clone());
+    let (code, stdout, stderr) = run_with_timeout(path, cmd_str, timeout, lines)?;
 
-                child.kill().unwrap();
+    let status = match code {
+        Some(0) => MutationStatus::NotKilled,
+        None => MutationStatus::Timeout,
+        _ => MutationStatus::Killed,
+    };
 
-                None
-            }
+    Ok(MutationResult {
+        mutation_id: mutation.patch_md5.clone(),
+        status,
+        stdout: Some(stdout),
+        stderr: Some(stderr),
+        exit_code: code,
+    })
+}
+
+fn checkout_command(mutation: &Mutation) -> String {
+    if let Some(base_commit) = &mutation.base_commit {
+        let reference = match mutation.pr_number {
+            Some(pr) => format!("pull/{}/head", pr),
+            None => mutation
+                .branch
+                .clone()
+                .unwrap_or_else(|| "master".to_string()),
         };
 
-        let stdout_str = stdout_handle.join().unwrap_or_default();
-        let stderr_str = stderr_handle.join().unwrap_or_default();
+        return format!(
+            "git reset --hard && git fetch origin {} && git checkout --detach {}",
+            reference, base_commit
+        );
+    }
 
-        let status = match status {
-            Some(0) => MutationStatus::NotKilled,
-            None => MutationStatus::Timeout,
-            _ => MutationStatus::Killed,
-        };
+    // Mutations stored before base commits were recorded.
+    if let Some(pr) = mutation.pr_number {
+        format!(
+            "git reset --hard && git checkout master && git branch | grep -v master | xargs git branch -D; gh pr checkout {} && git rebase --abort; git pull origin master --rebase",
+            pr,
+        )
+    } else {
+        let branch = mutation.branch.as_ref().unwrap();
+        format!(
+            "git reset --hard && git checkout master && git checkout {} && git pull origin {}",
+            branch, branch
+        )
+    }
+}
 
-        println!("Mutation {} status: {:?}", mutation.id, status);
-
-        let client = reqwest::Client::new();
-        let res = client
-            .post(&format!("{}/mutations/{}", server, mutation.id))
-            .body(serde_json::to_string(&MutationResult {
-                mutation_id: mutation.patch_md5,
-                status,
-                stdout: Some(stdout_str),
-                stderr: Some(stderr_str),
-            })?)
-            .header("Content-Type", "application/json")
-            .header("Authorization", token)
-            .send()
-            .await;
-
-        if let Err(e) = res {
-            println!("Error sending mutation status: {}", e);
-        } else {
-            println!("Mutation status sent: {}", res.unwrap().status());
+fn apply_patch(
+    mutation: &Mutation,
+    path: &str,
+) -> Result<(bool, String, String), Box<dyn std::error::Error>> {
+    let patch_path = format!("/tmp/{}.patch", mutation.id);
+    std::fs::write(&patch_path, &mutation.patch)?;
+
+    // Without fuzz the whole context has to match, so a patch never lands on
+    // code that changed around it.
+    run_command(
+        path,
+        &format!(
+            "patch --forward --batch --fuzz=0 {} {}",
+            mutation.file, patch_path
+        ),
+    )
+}
+
+/// Undoes a patch, along with the rejects left by one that did not apply, so
+/// the next mutation of a batch starts from the same checkout. Build outputs
+/// are kept for the next incremental build.
+fn revert_patch(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let (reverted, _, _) = run_command(
+        path,
+        "git reset --hard && git clean -fq -- '*.rej' '*.orig'",
+    )?;
+    if !reverted {
+        return Err("Failed to revert the patch".into());
+    }
+
+    Ok(())
+}
+
+/// Runs a short shell command to completion, returning whether it succeeded
+/// along with its output.
+fn run_command(
+    path: &str,
+    cmd_str: &str,
+) -> Result<(bool, String, String), Box<dyn std::error::Error>> {
+    let output = std::process::Command::new("bash")
+        .current_dir(path)
+        .arg("-c")
+        .arg(cmd_str)
+        .output()?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    print!("{}", stdout);
+    eprint!("{}", stderr);
+
+    Ok((output.status.success(), stdout, stderr))
+}
+
+/// Runs a shell command while streaming its output, to `live` as well when
+/// given, killing it once `timeout` seconds have elapsed. Returns `None` as
+/// exit code on timeout.
+fn run_with_timeout(
+    path: &str,
+    cmd_str: &str,
+    timeout: u64,
+    live: Option<mpsc::Sender<OutputLine>>,
+) -> Result<(Option<i32>, String, String), Box<dyn std::error::Error>> {
+    let mut cmd = std::process::Command::new("bash");
+    cmd.current_dir(path);
+    cmd.arg("-c");
+    cmd.stdout(std::process::Stdio::piped());
+    cmd.stderr(std::process::Stdio::piped());
+    cmd.arg(cmd_str);
+
+    let mut child = cmd.spawn()?;
+
+    // Stream stdout and stderr
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let stdout = BufReader::new(stdout);
+    let stderr = BufReader::new(stderr);
+
+    // separate thread to read stdout and stderr
+    let stdout_live = live.clone();
+    let stdout_handle = std::thread::spawn(move || {
+        let mut stdout_str = String::new();
+        for line in stdout.lines() {
+            let line = line.unwrap();
+            stdout_str = format!("{}\n{}", stdout_str, line);
+            println!("stdout: {}", line);
+            if let Some(live) = &stdout_live {
+                let _ = live.send(("stdout", line));
+            }
+        }
+
+        stdout_str
+    });
+
+    let stderr_handle = std::thread::spawn(move || {
+        let mut stderr_str = String::new();
+        for line in stderr.lines() {
+            let line = line.unwrap();
+            stderr_str = format!("{}\n{}", stderr_str, line);
+            println!("stderr: {}", line);
+            if let Some(live) = &live {
+                let _ = live.send(("stderr", line));
+            }
+        }
+
+        stderr_str
+    });
+
+    let code = match child.wait_timeout(Duration::from_secs(timeout))? {
+        Some(status) => status.code(),
+        None => {
+            println!("Timeout reached, killing process");
+            child.kill()?;
+            child.wait()?;
+
+            None
+        }
+    };
+
+    let stdout_str = stdout_handle.join().unwrap_or_default();
+    let stderr_str = stderr_handle.join().unwrap_or_default();
+
+    Ok((code, stdout_str, stderr_str))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGINAL: &str = "int Foo(int a)\n{\n    int b = 2;\n    if (a == b) {\n        return 1;\n    }\n    return 0;\n}\n";
+
+    const PATCH: &str = "--- a/foo.cpp\n+++ b/foo.cpp\n@@ -1,7 +1,7 @@\n int Foo(int a)\n {\n     int b = 2;\n-    if (a == b) {\n+    if (a != b) {\n         return 1;\n     }\n     return 0;\n";
+
+    #[test]
+    fn test_patches_only_apply_on_the_code_they_were_made_for() {
+        for (id, code, expected) in [
+            (1, ORIGINAL.to_string(), MutationStatus::NotKilled),
+            // Shifted code is the same code.
+            (
+                2,
+                format!("#include <foo.h>\n\n{}", ORIGINAL),
+                MutationStatus::NotKilled,
+            ),
+            // Context that drifted is not, even where fuzz would apply it.
+            (
+                3,
+                ORIGINAL.replace("int Foo(int a)", "int Foo(int a, int c)"),
+                MutationStatus::Stale,
+            ),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::write(dir.path().join("foo.cpp"), &code).unwrap();
+            let mutation = Mutation {
+                id,
+                patch_md5: format!("md5-{}", id),
+                file: "foo.cpp".to_string(),
+                patch: PATCH.to_string(),
+                ..Default::default()
+            };
+
+            let path = dir.path().to_str().unwrap();
+            let result =
+                test_mutation(&mutation, path, "true", "grep -q 'a != b' foo.cpp", 10).unwrap();
+            assert_eq!(result.status, expected, "{}", code);
         }
     }
 }